@@ -15,7 +15,7 @@ fn main() {
             starting_zoom: 14,
             tile_quality: 256.0,
             cache_dir: "cache".to_string(),
-            starting_url: None,
+            ..Default::default()
         })
         .add_systems(Startup, setup_camera)
         .add_systems(Update, handle_mouse)
@@ -1,196 +1,690 @@
 use std::{
-    collections::HashMap,
-    fs,
     io::{BufReader, Cursor, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 
 use bevy::{
     asset::RenderAssetUsages,
     image::Image,
+    log::warn,
     render::render_resource::{Extent3d, TextureDimension, TextureFormat},
 };
-use image::ImageReader;
+use image::{imageops, imageops::FilterType, ImageFormat, ImageReader, RgbaImage};
 use mvt_reader::Reader;
 
 use raqote::{
     AntialiasMode, DrawOptions, DrawTarget, PathBuilder, SolidSource, Source, StrokeStyle,
 };
-use std::path::PathBuf;
 use ureq::Agent;
 
-use crate::{tile_width_meters, TileType};
+#[cfg(any(feature = "mbtiles", feature = "pmtiles"))]
+use crate::sources::archive_path;
+#[cfg(feature = "wmts")]
+use crate::wmts::WmtsCapabilities;
+use crate::{
+    cache::{CacheLimits, CacheMetadata, DiskCache, MemoryCache},
+    compression::{decompress, decompress_vector_tile},
+    error::TileError,
+    provider::{build_tile_url, is_url_template, template_for_legacy_origin, ApiKey, TileLayer},
+    retry::{retry_after, RetryPolicy},
+    sources::{ArchiveView, OpenSources, SourceTile, TileData, TileSource},
+    tile_width_meters, TileType,
+};
+
+/// When the client goes to the network for tiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// Cached tiles are used while fresh and revalidated once they expire.
+    #[default]
+    Online,
+    /// Cached tiles are used however old they are, only missing tiles are
+    /// fetched.
+    CacheFirst,
+    /// Only cached tiles are shown and no request is ever sent. Tiles missing
+    /// from the cache are treated as tiles without data.
+    CacheOnly,
+}
 
 #[derive(Debug, Clone)]
 pub struct TileRequestClient {
     agent: Agent,
-    cache_dir: String,
-    pub tile_web_origin: HashMap<String, (bool, TileType)>,
+    cache: DiskCache,
+    memory_cache: MemoryCache,
+    sources: OpenSources,
+    pub network_mode: NetworkMode,
+    pub retry_policy: RetryPolicy,
+    /// Sent with every request unless a layer sets its own. Tile usage
+    /// policies such as OpenStreetMap's require it to identify the app.
+    pub user_agent: String,
+    /// The layer stack, see [`TileRequestClient::get_enabled_tile_web_origins`] for the draw order.
+    pub tile_web_origin: Vec<TileLayer>,
+    /// Set when the layers have to be fetched again.
     pub tile_web_origin_changed: bool,
+    /// Set when only the opacity, visibility or order of the layers changed.
+    pub tile_layer_style_changed: bool,
+    layers_generation: u64,
 }
 
 impl Default for TileRequestClient {
     fn default() -> Self {
-        let mut tile_web_origin = HashMap::default();
+        let mut tile_web_origin = vec![
+            TileLayer::new(
+                "https://tile.openstreetmap.org/{z}/{x}/{y}.png".to_string(),
+                TileType::Raster,
+            )
+            .with_attribution(
+                "© OpenStreetMap contributors",
+                Some("https://www.openstreetmap.org/copyright"),
+            ),
+            TileLayer::new(
+                "https://mt{s}.google.com/vt/lyrs=y&x={x}&y={y}&z={z}".to_string(),
+                TileType::Raster,
+            )
+            .with_subdomains(["0", "1", "2", "3"])
+            .with_attribution(
+                "Map data © Google",
+                Some("https://www.google.com/permissions/geoguidelines/"),
+            ),
+            TileLayer::new(
+                "https://mt{s}.google.com/vt/lyrs=m&x={x}&y={y}&z={z}".to_string(),
+                TileType::Raster,
+            )
+            .with_subdomains(["0", "1", "2", "3"])
+            .with_attribution(
+                "Map data © Google",
+                Some("https://www.google.com/permissions/geoguidelines/"),
+            ),
+            TileLayer::new(
+                "https://mt{s}.google.com/vt/lyrs=s&x={x}&y={y}&z={z}".to_string(),
+                TileType::Raster,
+            )
+            .with_subdomains(["0", "1", "2", "3"])
+            .with_attribution(
+                "Map data © Google",
+                Some("https://www.google.com/permissions/geoguidelines/"),
+            ),
+            TileLayer::new(
+                "https://tiles.openfreemap.org/planet/20250122_001001_pt/{z}/{x}/{y}.pbf"
+                    .to_string(),
+                TileType::Vector,
+            )
+            .with_attribution(
+                "OpenFreeMap © OpenMapTiles Data from OpenStreetMap",
+                Some("https://openfreemap.org"),
+            ),
+        ];
+        // Google hybrid is shown by default
+        for layer in tile_web_origin.iter_mut() {
+            layer.enabled = layer.url.contains("lyrs=y");
+        }
 
-        tile_web_origin.insert(
-            "https://tile.openstreetmap.org".to_string(),
-            (false, TileType::Raster),
-        );
-        tile_web_origin.insert(
-            "https://mt1.google.com/vt/lyrs=y".to_string(),
-            (true, TileType::Raster),
-        );
-        tile_web_origin.insert(
-            "https://mt1.google.com/vt/lyrs=m".to_string(),
-            (false, TileType::Raster),
-        );
-        tile_web_origin.insert(
-            "https://mt1.google.com/vt/lyrs=s".to_string(),
-            (false, TileType::Raster),
-        );
-        tile_web_origin.insert(
-            "https://tiles.openfreemap.org/planet/20250122_001001_pt".to_string(),
-            (false, TileType::Vector),
-        );
+        // Error statuses are handled by the retry policy, which needs the headers
         let config = Agent::config_builder()
             .timeout_global(Some(Duration::from_secs(5)))
+            .http_status_as_error(false)
             .build();
         let agent: Agent = config.into();
         TileRequestClient {
             agent,
             // Change this to be in a cache dir
-            cache_dir: "cache".to_string(),
+            cache: DiskCache::new("cache"),
+            memory_cache: MemoryCache::default(),
+            sources: OpenSources::default(),
+            network_mode: NetworkMode::default(),
+            retry_policy: RetryPolicy::default(),
+            user_agent: concat!("bevy_map_viewer/", env!("CARGO_PKG_VERSION")).to_string(),
             tile_web_origin,
             tile_web_origin_changed: false,
+            tile_layer_style_changed: false,
+            layers_generation: 0,
         }
     }
 }
 
 impl TileRequestClient {
     pub fn new(cache_dir: String, url: Option<String>) -> Self {
-        let mut me = TileRequestClient::default();
-        me.cache_dir = cache_dir;
+        let mut me = TileRequestClient {
+            cache: DiskCache::new(cache_dir),
+            ..Default::default()
+        };
         if let Some(url) = url {
-            if me.tile_web_origin.contains_key(&url) {
-                me.enable_tile_web_origin(&url);
+            if me.get_tile_layer(&url).is_some() {
+                me.enable_only_tile_web_origin(&url);
             } else {
+                me.disable_all_tile_web_origins();
                 me.add_tile_web_origin(url, true, TileType::Raster);
             }
         }
         me
     }
 
-    pub fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, image::ImageError> {
-        let (_, tile_type) = self.get_enabled_tile_web_origins().unwrap().1;
-        let extension = match tile_type {
-            TileType::Raster => "png",
-            TileType::Vector => "pbf",
-        };
+    pub fn get_tile(
+        &self,
+        layer_url: &str,
+        x: u64,
+        y: u64,
+        zoom: u64,
+    ) -> Result<Option<Vec<u8>>, TileError> {
+        let layer = self
+            .get_tile_layer(layer_url)
+            .filter(|layer| layer.enabled)
+            .ok_or_else(|| TileError::NoProvider(layer_url.to_string()))?;
+
+        if let Some(rgba) = self
+            .memory_cache
+            .get(&layer.url, x, y, zoom, layer.tile_size)
+        {
+            return Ok(Some(rgba));
+        }
+        // Tiles of other sources are not copied into the disk cache
+        if let Some(source) = self.tile_source(layer)? {
+            let tile_type = source.tile_type().unwrap_or(layer.tile_type);
+            let Some(tile) = source.get_tile(x, y, zoom)? else {
+                return Ok(None);
+            };
+            let expires = tile.expires;
+            let rgba = decode_source_tile(tile_type, tile, zoom, layer.tile_size)?;
+            self.memory_cache.insert(
+                &layer.url,
+                x,
+                y,
+                zoom,
+                layer.tile_size,
+                rgba.clone(),
+                expires,
+            );
+            return Ok(Some(rgba));
+        }
+        let tile = self.load_tile(layer, x, y, zoom)?;
+        if let Some(rgba) = &tile {
+            self.memory_cache
+                .insert(&layer.url, x, y, zoom, layer.tile_size, rgba.clone(), None);
+        }
+        Ok(tile)
+    }
+
+    // Reads and decodes a tile of a tile server that is not in the memory cache
+    fn load_tile(
+        &self,
+        layer: &TileLayer,
+        x: u64,
+        y: u64,
+        zoom: u64,
+    ) -> Result<Option<Vec<u8>>, TileError> {
+        let tile_type = layer.tile_type;
 
-        let url = self.get_enabled_tile_web_origins().unwrap().0;
-        let mut cache_dir = PathBuf::from(self.cache_dir.clone());
-        cache_dir.push(url.clone());
-        let mut cache_file = cache_dir.clone();
-        cache_file.push(format!("{}_{}_{}.{}", zoom, x, y, extension));
+        let cache_file = self.cache_path(layer, x, y, zoom);
         // Check if the file exists in the cache
-        if cache_file.exists() {
-            return match tile_type {
-                TileType::Raster => {
-                    decode_image(fs::read(&cache_file).expect("Failed to read cache file"))
+        let mut cached = self.cache.read(&cache_file)?;
+        let metadata = CacheMetadata::read(&cache_file);
+        if let Some(bytes) = &cached {
+            // Tiles cached before metadata was kept are revalidated once
+            let fresh = metadata.as_ref().is_some_and(CacheMetadata::is_fresh);
+            if fresh || self.network_mode != NetworkMode::Online {
+                match decode_tile(tile_type, bytes.clone(), zoom, layer.tile_size) {
+                    Ok(tile) => return Ok(Some(tile)),
+                    Err(err) => {
+                        self.quarantine(&cache_file, &err);
+                        cached = None;
+                    }
                 }
-                TileType::Vector => ofm_to_data_image(
-                    fs::read(&cache_file)
-                        .expect("Failed to read cache file")
-                        .clone(),
-                    256,
-                    zoom as u32,
-                ),
-            };
+            }
+        }
+        if self.network_mode == NetworkMode::CacheOnly {
+            return Ok(None);
         }
+        let req = request_url(layer, x, y, zoom);
 
-        let mut req = format!("{}/{}/{}/{}.{}", url, zoom, x, y, extension);
-        if url.contains("google") {
-            req = format!("{}&x={x}&y={y}&z={zoom}", url);
-        }
-
-        // If not in cache, fetch from the network
-        let mut status = 429;
-        while status == 429 {
-            if let Ok(mut response) = self.agent.get(req.as_str()).call() {
-                if response.status() == 200 {
-                    let mut reader: BufReader<Box<dyn Read + Send + Sync>> =
-                        BufReader::new(Box::new(response.body_mut().as_reader()));
-                    let mut bytes = Vec::new();
-                    reader
-                        .read_to_end(&mut bytes)
-                        .expect("Failed to read bytes from response");
-
-                    // Save to cache
-                    fs::create_dir_all(&cache_dir).expect("Failed to create cache directory");
-                    fs::write(&cache_file, &bytes).expect("Failed to write cache file");
-                    return match tile_type {
-                        TileType::Raster => decode_image(bytes),
-                        TileType::Vector => ofm_to_data_image(bytes.clone(), 256, zoom as u32),
-                    };
-                } else if response.status() == 429 {
-                    std::thread::sleep(std::time::Duration::from_secs(5));
-                } else {
-                    status = 0;
+        // Only send validators when there are cached bytes to fall back on
+        let validators = metadata.as_ref().filter(|_| cached.is_some());
+        let fetched = match self.fetch(&req, layer, validators) {
+            Ok(Fetched::NotModified(metadata)) => {
+                // Validators are only sent with cached bytes, so a 304 without
+                // them is a protocol error rather than a broken cache entry
+                let Some(bytes) = cached else {
+                    return Err(TileError::HttpStatus(304));
+                };
+                match decode_tile(tile_type, bytes, zoom, layer.tile_size) {
+                    Ok(tile) => {
+                        if let Err(err) = self.cache.update_metadata(&cache_file, &metadata) {
+                            warn!(
+                                "Failed to update cache metadata of {:?}: {}",
+                                cache_file, err
+                            );
+                        }
+                        return Ok(Some(tile));
+                    }
+                    // The provider confirmed a tile that is broken on disk
+                    Err(err) => {
+                        self.quarantine(&cache_file, &err);
+                        self.fetch(&req, layer, None)
+                    }
                 }
             }
+            Ok(fetched) => Ok(fetched),
+            // A stale tile is better than no tile
+            Err(err) => {
+                let Some(bytes) = cached else {
+                    return Err(err);
+                };
+                warn!("Using stale cached tile {:?}: {}", cache_file, err);
+                return decode_tile(tile_type, bytes, zoom, layer.tile_size)
+                    .inspect_err(|decode_err| self.quarantine(&cache_file, decode_err))
+                    .map(Some);
+            }
+        };
+
+        let (bytes, metadata) = fetched?.into_modified()?;
+        // Only tiles that decode are cached, so a bad response is fetched again
+        let tile = decode_tile(tile_type, bytes.clone(), zoom, layer.tile_size)?;
+        // A tile that could not be cached can still be shown
+        if let Err(err) = self.cache.write(&cache_file, &bytes, &metadata) {
+            warn!(
+                "Failed to write {:?} to the tile cache: {}",
+                cache_file, err
+            );
+        }
+        Ok(Some(tile))
+    }
+
+    // A cached tile that can't be decoded, usually one truncated by a crash
+    // of an earlier version, is moved aside so that it is fetched again
+    fn quarantine(&self, cache_file: &Path, err: &TileError) {
+        warn!(
+            "Quarantining cached tile {:?} that failed to decode: {}",
+            cache_file, err
+        );
+        if let Err(err) = self.cache.quarantine(cache_file) {
+            warn!("Failed to quarantine {:?}: {}", cache_file, err);
+        }
+    }
+
+    /// Whether tiles of the layer can be downloaded into the disk cache.
+    /// `false` for layers read from a local source, which need no download.
+    /// Fails if there is no such layer or the client is in cache-only mode.
+    pub fn can_download(&self, layer_url: &str) -> Result<bool, TileError> {
+        let layer = self
+            .get_tile_layer(layer_url)
+            .ok_or_else(|| TileError::NoProvider(layer_url.to_string()))?;
+        if self.tile_source(layer)?.is_some() {
+            return Ok(false);
+        }
+        if self.network_mode == NetworkMode::CacheOnly {
+            return Err(TileError::Offline);
+        }
+        Ok(true)
+    }
+
+    /// Stores a tile of a tile server in the disk cache without decoding it,
+    /// so that it is available offline. Returns the number of bytes
+    /// downloaded, or `None` if no request was needed because the cached tile
+    /// is still fresh or the layer is not served over the network.
+    pub fn download_tile(
+        &self,
+        layer_url: &str,
+        x: u64,
+        y: u64,
+        zoom: u64,
+    ) -> Result<Option<u64>, TileError> {
+        if !self.can_download(layer_url)? {
+            return Ok(None);
+        }
+        let layer = self
+            .get_tile_layer(layer_url)
+            .ok_or_else(|| TileError::NoProvider(layer_url.to_string()))?;
+
+        let cache_file = self.cache_path(layer, x, y, zoom);
+        let cached = self.cache.read(&cache_file)?.is_some();
+        let metadata = CacheMetadata::read(&cache_file).filter(|_| cached);
+        if metadata.as_ref().is_some_and(CacheMetadata::is_fresh) {
+            return Ok(None);
+        }
+        match self.fetch(&request_url(layer, x, y, zoom), layer, metadata.as_ref())? {
+            Fetched::Modified(bytes, metadata) => {
+                self.cache.write(&cache_file, &bytes, &metadata)?;
+                Ok(Some(bytes.len() as u64))
+            }
+            Fetched::NotModified(metadata) => {
+                self.cache.update_metadata(&cache_file, &metadata)?;
+                Ok(Some(0))
+            }
+        }
+    }
+
+    /// Downloads and parses the capabilities of a WMTS service. `url` is
+    /// either the `WMTSCapabilities.xml` document or the KVP endpoint of the
+    /// service. The `api_key` and `headers` are sent as they would be for a
+    /// layer of the service. This blocks until the response arrives, and fails
+    /// in [`NetworkMode::CacheOnly`].
+    #[cfg(feature = "wmts")]
+    pub fn fetch_wmts_capabilities(
+        &self,
+        url: &str,
+        api_key: Option<ApiKey>,
+        headers: &[(String, String)],
+    ) -> Result<WmtsCapabilities, TileError> {
+        if self.network_mode == NetworkMode::CacheOnly {
+            return Err(TileError::Offline);
+        }
+        let lowercase = url.to_ascii_lowercase();
+        let path = lowercase.split('?').next().unwrap_or_default();
+        let mut url = if path.ends_with(".xml") || lowercase.contains("getcapabilities") {
+            url.to_string()
+        } else {
+            let separator = if url.contains('?') { "&" } else { "?" };
+            format!(
+                "{}{}SERVICE=WMTS&REQUEST=GetCapabilities&VERSION=1.0.0",
+                url, separator
+            )
+        };
+        if let Some(api_key) = &api_key {
+            api_key.apply_to_url(&mut url);
+        }
+        let mut layer = TileLayer::new(url.clone(), TileType::Raster);
+        layer.api_key = api_key;
+        layer.headers = headers.to_vec();
+        let (bytes, _) = self.fetch(&url, &layer, None)?.into_modified()?;
+        let xml = String::from_utf8(bytes).map_err(|err| TileError::Source(Box::new(err)))?;
+        WmtsCapabilities::parse(&xml)
+    }
+
+    // Tiles are cached under the y index of the provider's tile scheme
+    fn cache_path(&self, layer: &TileLayer, x: u64, y: u64, zoom: u64) -> PathBuf {
+        self.cache.tile_path(
+            &layer.url,
+            x,
+            layer.scheme.y(y, zoom),
+            zoom,
+            tile_extension(layer.tile_type),
+        )
+    }
+
+    fn fetch(
+        &self,
+        url: &str,
+        layer: &TileLayer,
+        validators: Option<&CacheMetadata>,
+    ) -> Result<Fetched, TileError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (result, retry_after) = self.fetch_once(url, layer, validators);
+            match result {
+                Ok(fetched) => return Ok(fetched),
+                Err(err) => match self.retry_policy.retry_delay(attempt, &err, retry_after) {
+                    Some(delay) => std::thread::sleep(delay),
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+
+    // Also returns the Retry-After of failed responses
+    fn fetch_once(
+        &self,
+        url: &str,
+        layer: &TileLayer,
+        validators: Option<&CacheMetadata>,
+    ) -> (Result<Fetched, TileError>, Option<Duration>) {
+        let mut request = self.agent.get(url);
+        if !layer
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("user-agent"))
+        {
+            request = request.header("User-Agent", &self.user_agent);
+        }
+        for (name, value) in &layer.headers {
+            request = request.header(name, value);
+        }
+        if let Some(ApiKey::Header { name, value }) = &layer.api_key {
+            request = request.header(name, value);
+        }
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header("If-Modified-Since", last_modified);
+            }
+        }
+
+        let mut response = match request.call() {
+            Ok(response) => response,
+            Err(err) => return (Err(err.into()), None),
+        };
+        let status = response.status().as_u16();
+        if status >= 400 {
+            return (
+                Err(TileError::HttpStatus(status)),
+                retry_after(response.headers()),
+            );
+        }
+
+        let metadata = CacheMetadata::from_headers(response.headers(), validators);
+        if status == 304 {
+            return (Ok(Fetched::NotModified(metadata)), None);
+        }
+
+        let mut reader: BufReader<Box<dyn Read + Send + Sync>> =
+            BufReader::new(Box::new(response.body_mut().as_reader()));
+        let mut bytes = Vec::new();
+        let result = reader
+            .read_to_end(&mut bytes)
+            .map(|_| Fetched::Modified(bytes, metadata))
+            .map_err(|err| TileError::Network(err.into()));
+        (result, None)
+    }
+}
+
+impl TileRequestClient {
+    /// The source a layer is read from, `None` for tile servers.
+    fn tile_source(&self, layer: &TileLayer) -> Result<Option<Arc<dyn TileSource>>, TileError> {
+        if let Some(source) = &layer.source {
+            return Ok(Some(source.clone()));
+        }
+        #[cfg(feature = "mbtiles")]
+        if let Some(path) = archive_path(&layer.url, "mbtiles") {
+            return Ok(Some(self.sources.mbtiles(path)?));
+        }
+        #[cfg(feature = "pmtiles")]
+        if let Some(path) = archive_path(&layer.url, "pmtiles") {
+            return Ok(Some(self.sources.pmtiles(path)?));
+        }
+        Ok(self
+            .sources
+            .local(&layer.url, layer.tile_type, layer.scheme)
+            .map(|source| source as Arc<dyn TileSource>))
+    }
+}
+
+enum Fetched {
+    Modified(Vec<u8>, CacheMetadata),
+    // The cached bytes are still current
+    NotModified(CacheMetadata),
+}
+
+impl Fetched {
+    // Servers and proxies may answer 304 even to requests without
+    // validators, which leaves no bytes to use
+    fn into_modified(self) -> Result<(Vec<u8>, CacheMetadata), TileError> {
+        match self {
+            Fetched::Modified(bytes, metadata) => Ok((bytes, metadata)),
+            Fetched::NotModified(_) => Err(TileError::HttpStatus(304)),
         }
-        Err(image::ImageError::IoError(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to fetch tile: {}", status),
-        )))
     }
 }
 
 impl TileRequestClient {
+    /// Limits the size and age of the tile cache, evicting the least recently
+    /// used tiles first.
+    pub fn set_cache_limits(&mut self, limits: CacheLimits) {
+        self.cache.set_limits(limits);
+    }
+
+    /// Current size of the tile cache in bytes.
+    pub fn cache_size(&self) -> u64 {
+        self.cache.size()
+    }
+
+    /// Limits the memory used by decoded tiles, 128 MiB by default. A decoded
+    /// tile takes `tile_size * tile_size * 4` bytes.
+    pub fn set_memory_cache_limit(&mut self, max_size: usize) {
+        self.memory_cache.set_max_size(max_size);
+    }
+
+    /// Memory used by decoded tiles in bytes.
+    pub fn memory_cache_size(&self) -> usize {
+        self.memory_cache.size()
+    }
+
+    /// The view stored in the first enabled source that has one, used instead
+    /// of the configured starting location and zoom.
+    pub fn archive_view(&self) -> Option<ArchiveView> {
+        self.get_enabled_tile_web_origins()
+            .iter()
+            .find_map(|layer| match self.tile_source(layer) {
+                Ok(source) => source?.view(),
+                Err(err) => {
+                    warn!("Failed to open {}: {}", layer.url, err);
+                    None
+                }
+            })
+    }
+}
+
+impl TileRequestClient {
+    /// Adds a layer on top of the stack, or replaces the layer with the same url.
+    pub fn add_tile_layer(&mut self, layer: TileLayer) {
+        self.tile_web_origin_changed = true;
+        self.mark_layers_changed();
+        self.memory_cache.remove_layer(&layer.url);
+        if let Some(existing) = self.get_tile_layer_mut(&layer.url) {
+            *existing = layer;
+        } else {
+            self.tile_web_origin.push(layer);
+        }
+    }
+
     pub fn add_tile_web_origin(&mut self, url: String, enabled: bool, tile_type: TileType) {
+        let mut layer = TileLayer::new(url, tile_type);
+        layer.enabled = enabled;
+        self.add_tile_layer(layer);
+    }
+
+    pub fn remove_tile_layer(&mut self, url: &str) {
         self.tile_web_origin_changed = true;
-        self.tile_web_origin.insert(url, (enabled, tile_type));
+        self.mark_layers_changed();
+        self.memory_cache.remove_layer(url);
+        self.tile_web_origin.retain(|layer| layer.url != url);
+    }
+
+    /// The layer with the given url. The origins the built-in providers had
+    /// before url templates, such as `https://mt1.google.com/vt/lyrs=y`, find
+    /// their provider too.
+    pub fn get_tile_layer(&self, url: &str) -> Option<&TileLayer> {
+        let url = self.resolve_layer_url(url);
+        self.tile_web_origin.iter().find(|layer| layer.url == url)
+    }
+
+    pub fn get_tile_layer_mut(&mut self, url: &str) -> Option<&mut TileLayer> {
+        let url = self.resolve_layer_url(url).to_string();
+        self.mark_layers_changed();
+        self.tile_web_origin
+            .iter_mut()
+            .find(|layer| layer.url == url)
+    }
+
+    // A layer added under a legacy origin keeps that url
+    fn resolve_layer_url<'a>(&self, url: &'a str) -> &'a str {
+        match template_for_legacy_origin(url) {
+            Some(template) if !self.tile_web_origin.iter().any(|layer| layer.url == url) => {
+                template
+            }
+            _ => url,
+        }
     }
 
     pub fn enable_tile_web_origin(&mut self, url: &str) {
-        if let Some((enabled, _)) = self.tile_web_origin.get_mut(url) {
+        if let Some(layer) = self.get_tile_layer_mut(url) {
+            layer.enabled = true;
             self.tile_web_origin_changed = true;
-            *enabled = true;
         }
     }
 
-    pub fn disable_all_tile_web_origins(&mut self) {
-        for (_, (enabled, _)) in self.tile_web_origin.iter_mut() {
+    pub fn disable_tile_web_origin(&mut self, url: &str) {
+        if let Some(layer) = self.get_tile_layer_mut(url) {
+            layer.enabled = false;
             self.tile_web_origin_changed = true;
-            *enabled = false;
         }
     }
 
+    pub fn disable_all_tile_web_origins(&mut self) {
+        for layer in self.tile_web_origin.iter_mut() {
+            layer.enabled = false;
+        }
+        self.tile_web_origin_changed = true;
+        self.mark_layers_changed();
+    }
+
     pub fn enable_only_tile_web_origin(&mut self, url: &str) {
         self.disable_all_tile_web_origins();
+        self.enable_tile_web_origin(url);
+    }
 
-        if let Some((enabled, _)) = self.tile_web_origin.get_mut(url) {
-            *enabled = true;
-            // Tell the chunks to upadte completely,
+    pub fn set_layer_opacity(&mut self, url: &str, opacity: f32) {
+        if let Some(layer) = self.get_tile_layer_mut(url) {
+            layer.opacity = opacity.clamp(0.0, 1.0);
+            self.tile_layer_style_changed = true;
         }
     }
 
-    pub fn get_enabled_tile_web_origins(&self) -> Option<(String, (bool, TileType))> {
-        for (url, (enabled, tile_type)) in self.tile_web_origin.clone() {
-            if enabled {
-                return Some((url, (enabled, tile_type)));
-            }
+    pub fn set_layer_visibility(&mut self, url: &str, visible: bool) {
+        if let Some(layer) = self.get_tile_layer_mut(url) {
+            layer.visible = visible;
+            self.tile_layer_style_changed = true;
+        }
+    }
+
+    pub fn set_layer_z_order(&mut self, url: &str, z_order: i32) {
+        if let Some(layer) = self.get_tile_layer_mut(url) {
+            layer.z_order = z_order;
+            self.tile_layer_style_changed = true;
         }
-        None
+    }
+
+    /// Incremented whenever the layer stack may have changed, so that values
+    /// derived from it only have to be computed again when it differs.
+    pub fn layers_generation(&self) -> u64 {
+        self.layers_generation
+    }
+
+    /// Call after changing `tile_web_origin` directly.
+    pub fn mark_layers_changed(&mut self) {
+        self.layers_generation += 1;
+    }
+
+    /// Returns the enabled layers from the bottom of the stack to the top.
+    /// Layers with the same `z_order` keep the order they were added in.
+    pub fn get_enabled_tile_web_origins(&self) -> Vec<TileLayer> {
+        let mut layers: Vec<TileLayer> = self
+            .tile_web_origin
+            .iter()
+            .filter(|layer| layer.enabled)
+            .cloned()
+            .collect();
+        layers.sort_by_key(|layer| layer.z_order);
+        layers
     }
 }
 
-pub fn buffer_to_bevy_image(data: Vec<u8>, tile_quality: u32) -> Image {
+pub fn buffer_to_bevy_image(data: Vec<u8>, tile_size: u32) -> Image {
     Image::new(
         Extent3d {
-            width: tile_quality,
-            height: tile_quality,
+            width: tile_size,
+            height: tile_size,
             depth_or_array_layers: 1,
         },
         TextureDimension::D2,
@@ -200,21 +694,137 @@ pub fn buffer_to_bevy_image(data: Vec<u8>, tile_quality: u32) -> Image {
     )
 }
 
-// Helper convert png to uncompressed image
-fn decode_image(data: Vec<u8>) -> Result<Vec<u8>, image::ImageError> {
-    // Failed to decode PNG data: Decoding(DecodingError { format: Exact(Jpeg), underlying: Some("No more bytes") })
-    let img = ImageReader::new(Cursor::new(data))
-        .with_guessed_format()?
-        .decode()?;
-    let rgba = img.to_rgba8();
-    Ok(rgba.to_vec())
+fn tile_extension(tile_type: TileType) -> &'static str {
+    match tile_type {
+        TileType::Raster => "png",
+        TileType::Vector => "pbf",
+    }
+}
+
+// The url a tile is requested from, with the API key if it is a query parameter
+fn request_url(layer: &TileLayer, x: u64, y: u64, zoom: u64) -> String {
+    // Plain origins without placeholders use the standard {z}/{x}/{y} layout
+    let mut url = if is_url_template(&layer.url) {
+        build_tile_url(
+            &layer.url,
+            &layer.subdomains,
+            layer.retina,
+            layer.scheme,
+            x,
+            y,
+            zoom,
+        )
+    } else {
+        format!(
+            "{}/{}/{}/{}.{}",
+            layer.url,
+            zoom,
+            x,
+            layer.scheme.y(y, zoom),
+            tile_extension(layer.tile_type)
+        )
+    };
+    if let Some(api_key) = &layer.api_key {
+        api_key.apply_to_url(&mut url);
+    }
+    url
+}
+
+fn decode_source_tile(
+    tile_type: TileType,
+    tile: SourceTile,
+    zoom: u64,
+    tile_size: u32,
+) -> Result<Vec<u8>, TileError> {
+    match tile.data {
+        TileData::Encoded(bytes) => match tile_type {
+            TileType::Raster => {
+                let format = tile
+                    .content_type
+                    .as_deref()
+                    .and_then(ImageFormat::from_mime_type);
+                decode_image(bytes, format, tile_size)
+            }
+            TileType::Vector => {
+                let bytes = match tile.compression {
+                    Some(compression) => decompress(bytes, compression)?,
+                    None => decompress_vector_tile(bytes)?,
+                };
+                ofm_to_data_image(bytes, tile_size, zoom as u32)
+            }
+        },
+        TileData::Rgba { pixels, size } => {
+            if pixels.len() != (size * size * 4) as usize {
+                return Err(TileError::Source(
+                    format!(
+                        "Expected {} bytes of RGBA pixels but got {}",
+                        size * size * 4,
+                        pixels.len()
+                    )
+                    .into(),
+                ));
+            }
+            let rgba =
+                RgbaImage::from_raw(size, size, pixels).expect("Pixel count was checked above");
+            Ok(resample(rgba, tile_size))
+        }
+    }
+}
+
+/// Decodes a tile into `tile_size * tile_size` RGBA pixels. Vector tiles are
+/// rasterized at that size, raster tiles of another size are resampled.
+fn decode_tile(
+    tile_type: TileType,
+    data: Vec<u8>,
+    zoom: u64,
+    tile_size: u32,
+) -> Result<Vec<u8>, TileError> {
+    match tile_type {
+        TileType::Raster => decode_image(data, None, tile_size),
+        TileType::Vector => {
+            ofm_to_data_image(decompress_vector_tile(data)?, tile_size, zoom as u32)
+        }
+    }
+}
+
+// Helper convert png to uncompressed image, guessing the format if not given
+fn decode_image(
+    data: Vec<u8>,
+    format: Option<ImageFormat>,
+    tile_size: u32,
+) -> Result<Vec<u8>, TileError> {
+    let img = match format {
+        Some(format) => ImageReader::with_format(Cursor::new(data), format),
+        None => ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|err| TileError::Decode(image::ImageError::IoError(err)))?,
+    }
+    .decode()?;
+    Ok(resample(img.to_rgba8(), tile_size))
+}
+
+/// Resamples a square RGBA tile to `tile_size`, for tiles decoded before the
+/// tile size of their layer changed. `None` if the buffer isn't square.
+pub fn resample_rgba(data: Vec<u8>, tile_size: u32) -> Option<Vec<u8>> {
+    let size = ((data.len() / 4) as f64).sqrt() as u32;
+    let rgba = RgbaImage::from_raw(size, size, data).filter(|_| size > 0)?;
+    (rgba.len() == (size * size * 4) as usize).then(|| resample(rgba, tile_size))
+}
+
+// Providers don't always serve the size they are declared with, and custom
+// sources may use any size, but the texture has to match the layer
+fn resample(rgba: RgbaImage, tile_size: u32) -> Vec<u8> {
+    if rgba.width() == tile_size && rgba.height() == tile_size {
+        return rgba.into_raw();
+    }
+    imageops::resize(&rgba, tile_size, tile_size, FilterType::Triangle).into_raw()
 }
 
 /// This converts it to an image which is as many meters as the tile width This would be AAAMAAZZZING to multithread
 /// It would also be good to add a settings struct to control the colors, perhaps add background images and select what specificlly is rendered.
 // What would be good is if we slipt tile tiles into 4 when we start getting a zoom over the amount which cant go in anymore like over zoom = 16
-fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Result<Vec<u8>, image::ImageError> {
-    let tile = Reader::new(data).unwrap();
+fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Result<Vec<u8>, TileError> {
+    let tile = Reader::new(data)?;
     //let size_multiplyer = TILE_QUALITY as u32 / size ;
     let mut dt = DrawTarget::new(size as i32, size as i32);
 
@@ -256,7 +866,7 @@ fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Result<Vec<u8>, ima
     dt.set_transform(&raqote::Transform::scale(scale, scale));
 
     // Iterate over layers and features]
-    let layer_names = tile.get_layer_names().unwrap();
+    let layer_names = tile.get_layer_names()?;
     for (i, title) in layer_names.into_iter().enumerate() {
         for features in tile.get_features(i).iter() {
             for feature in features {
@@ -429,3 +1039,29 @@ fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Result<Vec<u8>, ima
 
     Ok(dt.get_data_u8().to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::TileScheme;
+
+    #[test]
+    fn tms_layers_flip_only_y() {
+        let mut layer = TileLayer::new(
+            "https://example.com/{z}/{x}/{y}?q={quadkey}&bbox={bbox}&row={-y}".to_string(),
+            TileType::Raster,
+        );
+        layer.scheme = TileScheme::Tms;
+        assert_eq!(
+            request_url(&layer, 0, 0, 1),
+            "https://example.com/1/0/1?q=0&bbox=-20037508.34,0.00,0.00,20037508.34&row=1"
+        );
+
+        let mut plain = TileLayer::new("https://example.com".to_string(), TileType::Raster);
+        plain.scheme = TileScheme::Tms;
+        assert_eq!(
+            request_url(&plain, 0, 0, 1),
+            "https://example.com/1/0/1.png"
+        );
+    }
+}
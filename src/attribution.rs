@@ -0,0 +1,133 @@
+//! Attribution of the map data currently shown.
+//!
+//! Most providers require their attribution to be visible whenever their
+//! tiles are. [`MapAttribution`] always holds the attributions of the enabled
+//! and visible layers, and with the `attribution` feature they are drawn in
+//! the bottom right corner of the window unless `show_overlay` is turned off.
+
+use bevy::prelude::*;
+
+use crate::{provider::Attribution, types::TileMapResources};
+
+/// The attributions of the layers on screen, from the bottom of the layer
+/// stack to the top, without duplicates.
+#[derive(Resource, Debug, Clone)]
+pub struct MapAttribution {
+    pub attributions: Vec<Attribution>,
+    /// Draw the attributions on the map. Turn this off when showing them
+    /// some other way.
+    pub show_overlay: bool,
+}
+
+impl Default for MapAttribution {
+    fn default() -> Self {
+        Self {
+            attributions: Vec::new(),
+            show_overlay: true,
+        }
+    }
+}
+
+impl MapAttribution {
+    /// The attribution texts joined into a single line.
+    pub fn text(&self) -> String {
+        self.attributions
+            .iter()
+            .map(|attribution| attribution.text.as_str())
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+}
+
+pub struct AttributionPlugin;
+
+impl Plugin for AttributionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MapAttribution>()
+            .add_systems(Update, update_attributions);
+        #[cfg(feature = "attribution")]
+        app.add_systems(Startup, spawn_attribution_overlay)
+            .add_systems(
+                Update,
+                update_attribution_overlay.after(update_attributions),
+            );
+    }
+}
+
+fn update_attributions(
+    res_manager: Res<TileMapResources>,
+    mut map_attribution: ResMut<MapAttribution>,
+    mut generation: Local<Option<u64>>,
+) {
+    // Only computed again when the layers changed
+    let client = &res_manager.tile_request_client;
+    if *generation == Some(client.layers_generation()) {
+        return;
+    }
+    *generation = Some(client.layers_generation());
+
+    let mut attributions: Vec<Attribution> = Vec::new();
+    for layer in client.get_enabled_tile_web_origins() {
+        if let Some(attribution) = layer.attribution.filter(|_| layer.visible) {
+            if !attributions.contains(&attribution) {
+                attributions.push(attribution);
+            }
+        }
+    }
+    // Only trigger change detection when the attributions actually changed
+    if map_attribution.attributions != attributions {
+        map_attribution.attributions = attributions;
+    }
+}
+
+#[cfg(feature = "attribution")]
+#[derive(Component)]
+struct AttributionOverlay;
+
+#[cfg(feature = "attribution")]
+fn spawn_attribution_overlay(mut commands: Commands) {
+    commands
+        .spawn((
+            Node {
+                position_type: PositionType::Absolute,
+                right: Val::Px(0.0),
+                bottom: Val::Px(0.0),
+                padding: UiRect::axes(Val::Px(6.0), Val::Px(2.0)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(1.0, 1.0, 1.0, 0.7)),
+            Visibility::Hidden,
+            AttributionOverlay,
+        ))
+        .with_child((
+            Text::default(),
+            TextFont {
+                font_size: 11.0,
+                ..default()
+            },
+            TextColor(Color::srgb(0.2, 0.2, 0.2)),
+        ));
+}
+
+#[cfg(feature = "attribution")]
+fn update_attribution_overlay(
+    map_attribution: Res<MapAttribution>,
+    mut overlay_query: Query<(&mut Visibility, &Children), With<AttributionOverlay>>,
+    mut text_query: Query<&mut Text>,
+) {
+    if !map_attribution.is_changed() {
+        return;
+    }
+    for (mut visibility, children) in overlay_query.iter_mut() {
+        *visibility = if map_attribution.show_overlay && !map_attribution.attributions.is_empty() {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        for child in children.iter() {
+            if let Ok(mut text) = text_query.get_mut(child) {
+                text.0 = map_attribution.text();
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Decompression of tile payloads.
+//!
+//! Vector tiles are often stored and served compressed, without anything but
+//! the bytes themselves telling so. Gzip and zlib are recognised by their
+//! headers. Brotli has no header, but an uncompressed MVT always starts with
+//! the tag of its first layer, so anything else is tried as brotli before
+//! being passed on unchanged.
+
+use std::io::Read;
+
+use brotli_decompressor::Decompressor;
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use crate::{error::TileError, sources::TileCompression};
+
+/// Tag of field 3, `layers`, with the length-delimited wire type.
+const MVT_LAYER_TAG: u8 = 0x1a;
+
+/// Returns the uncompressed bytes of a vector tile, whether it was
+/// compressed with gzip, zlib or brotli or not at all.
+pub fn decompress_vector_tile(bytes: Vec<u8>) -> Result<Vec<u8>, TileError> {
+    match bytes.as_slice() {
+        [0x1f, 0x8b, ..] => gunzip(&bytes),
+        [cmf, flg, ..] if is_zlib_header(*cmf, *flg) => {
+            read_all(ZlibDecoder::new(bytes.as_slice()))
+        }
+        // Empty tiles and plain MVT
+        [] | [MVT_LAYER_TAG, ..] => Ok(bytes),
+        _ => Ok(brotli(&bytes).unwrap_or(bytes)),
+    }
+}
+
+/// Decompresses a tile whose compression is known.
+pub fn decompress(bytes: Vec<u8>, compression: TileCompression) -> Result<Vec<u8>, TileError> {
+    match compression {
+        TileCompression::None => Ok(bytes),
+        TileCompression::Gzip => gunzip(&bytes),
+        TileCompression::Zlib => read_all(ZlibDecoder::new(bytes.as_slice())),
+        TileCompression::Brotli => brotli(&bytes),
+    }
+}
+
+pub fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, TileError> {
+    read_all(GzDecoder::new(bytes))
+}
+
+pub fn brotli(bytes: &[u8]) -> Result<Vec<u8>, TileError> {
+    read_all(Decompressor::new(bytes, 4096))
+}
+
+// Deflate with a window of at most 32 KiB and a valid header checksum
+fn is_zlib_header(cmf: u8, flg: u8) -> bool {
+    cmf & 0x0f == 8 && cmf >> 4 <= 7 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0
+}
+
+fn read_all(mut reader: impl Read) -> Result<Vec<u8>, TileError> {
+    let mut decompressed = Vec::new();
+    reader
+        .read_to_end(&mut decompressed)
+        .map_err(|err| TileError::Source(Box::new(err)))?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    // A tag, a length and the layer bytes
+    const TILE: &[u8] = &[MVT_LAYER_TAG, 3, b'a', b'b', b'c'];
+
+    #[test]
+    fn plain_tiles_pass_through() {
+        assert_eq!(decompress_vector_tile(TILE.to_vec()).unwrap(), TILE);
+        assert!(decompress_vector_tile(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn gzip_and_zlib() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(TILE).unwrap();
+        assert_eq!(
+            decompress_vector_tile(gzip.finish().unwrap()).unwrap(),
+            TILE
+        );
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(TILE).unwrap();
+        assert_eq!(
+            decompress_vector_tile(zlib.finish().unwrap()).unwrap(),
+            TILE
+        );
+    }
+
+    #[test]
+    fn brotli_without_header() {
+        // An uncompressed meta-block holding the tile, then an empty last one
+        let mut stream = vec![0x40, 0x00, 0x10];
+        stream.extend_from_slice(TILE);
+        stream.push(0x03);
+        assert_eq!(decompress_vector_tile(stream).unwrap(), TILE);
+    }
+
+    #[test]
+    fn unknown_bytes_are_left_alone() {
+        let bytes = vec![0xff, 0xff, 0xff];
+        assert_eq!(decompress_vector_tile(bytes.clone()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn known_compression_skips_detection() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(TILE).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(
+            decompress(gzip.clone(), TileCompression::Gzip).unwrap(),
+            TILE
+        );
+        assert_eq!(
+            decompress(gzip.clone(), TileCompression::None).unwrap(),
+            gzip
+        );
+        assert!(decompress(TILE.to_vec(), TileCompression::Zlib).is_err());
+    }
+}
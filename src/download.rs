@@ -0,0 +1,306 @@
+//! Downloading every tile of a region into the disk cache for offline use.
+//!
+//! Each download runs on its own thread, one tile at a time, so that it never
+//! competes with the tile workers for more than one connection. Requests go
+//! through the client's retry policy and are spaced out to stay below the
+//! configured rate. Progress is reported as [`RegionDownloadProgress`] events.
+
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    ecs::{
+        event::{Event, EventWriter},
+        resource::Resource,
+        system::Res,
+    },
+    log::warn,
+};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::{api::TileRequestClient, Tile, WorldSpaceRect};
+
+/// A region to download.
+#[derive(Debug, Clone)]
+pub struct RegionDownload {
+    pub bounds: WorldSpaceRect,
+    pub zoom: RangeInclusive<u32>,
+    /// Url of the layer to download.
+    pub layer: String,
+    /// Maximum number of requests per second, unlimited if `None`.
+    pub max_requests_per_second: Option<f32>,
+}
+
+impl RegionDownload {
+    pub fn new(
+        bounds: impl Into<WorldSpaceRect>,
+        zoom: RangeInclusive<u32>,
+        layer: String,
+    ) -> Self {
+        Self {
+            bounds: bounds.into(),
+            zoom,
+            layer,
+            max_requests_per_second: Some(4.0),
+        }
+    }
+
+    /// Every tile covering the region, from the lowest zoom level up.
+    pub fn tiles(&self) -> impl Iterator<Item = Tile> + '_ {
+        self.zoom.clone().flat_map(move |zoom| {
+            let (min, max) = self.tile_range(zoom);
+            (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| Tile::new(x, y, zoom)))
+        })
+    }
+
+    pub fn tile_count(&self) -> u64 {
+        self.zoom
+            .clone()
+            .map(|zoom| {
+                let (min, max) = self.tile_range(zoom);
+                (max.x - min.x + 1) as u64 * (max.y - min.y + 1) as u64
+            })
+            .sum()
+    }
+
+    // The top left and bottom right tiles, clamped to the tiles that exist
+    fn tile_range(&self, zoom: u32) -> (Tile, Tile) {
+        let last = (1_i64 << zoom).saturating_sub(1).min(i32::MAX as i64) as i32;
+        let clamp = |tile: Tile| Tile::new(tile.x.clamp(0, last), tile.y.clamp(0, last), zoom);
+        let top_left = clamp(self.bounds.top_left.to_tile_coords(zoom));
+        let bottom_right = clamp(self.bounds.bottom_right.to_tile_coords(zoom));
+        (
+            Tile::new(
+                top_left.x.min(bottom_right.x),
+                top_left.y.min(bottom_right.y),
+                zoom,
+            ),
+            Tile::new(
+                top_left.x.max(bottom_right.x),
+                top_left.y.max(bottom_right.y),
+                zoom,
+            ),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionDownloadStatus {
+    Running,
+    Paused,
+    Cancelled,
+    Finished,
+}
+
+/// Sent after every downloaded tile and whenever a download is paused,
+/// resumed, cancelled or finished.
+#[derive(Event, Debug, Clone)]
+pub struct RegionDownloadProgress {
+    /// The id of the [`RegionDownloadHandle`] of the download.
+    pub id: u64,
+    pub status: RegionDownloadStatus,
+    /// Tiles handled so far, including failed ones.
+    pub done: u64,
+    pub total: u64,
+    /// Bytes downloaded, tiles that were already cached don't count.
+    pub bytes: u64,
+    pub failed: u64,
+}
+
+/// Controls a running download. Dropping the handle does not stop it.
+#[derive(Debug, Clone)]
+pub struct RegionDownloadHandle {
+    id: u64,
+    control: Arc<Control>,
+}
+
+#[derive(Debug, Default)]
+struct Control {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    cancelled: bool,
+}
+
+impl RegionDownloadHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Stops after the tile being downloaded until resumed.
+    pub fn pause(&self) {
+        self.control.lock().paused = true;
+        self.control.changed.notify_all();
+    }
+
+    pub fn resume(&self) {
+        self.control.lock().paused = false;
+        self.control.changed.notify_all();
+    }
+
+    /// Stops the download for good. Tiles downloaded so far stay cached.
+    pub fn cancel(&self) {
+        self.control.lock().cancelled = true;
+        self.control.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.lock().paused
+    }
+}
+
+impl Control {
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Blocks while paused, returns false once cancelled
+    fn wait_while_paused(&self, report: impl Fn(RegionDownloadStatus)) -> bool {
+        let mut state = self.lock();
+        if state.paused && !state.cancelled {
+            report(RegionDownloadStatus::Paused);
+            while state.paused && !state.cancelled {
+                state = self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            if !state.cancelled {
+                report(RegionDownloadStatus::Running);
+            }
+        }
+        !state.cancelled
+    }
+}
+
+/// Starts region downloads and forwards their progress as events.
+#[derive(Resource)]
+pub struct RegionDownloads {
+    sender: Sender<RegionDownloadProgress>,
+    receiver: Receiver<RegionDownloadProgress>,
+    next_id: u64,
+}
+
+impl Default for RegionDownloads {
+    fn default() -> Self {
+        let (sender, receiver) = unbounded();
+        Self {
+            sender,
+            receiver,
+            next_id: 0,
+        }
+    }
+}
+
+impl RegionDownloads {
+    /// Starts downloading the region in the background with a clone of the
+    /// client, so later changes to the client don't affect it.
+    pub fn start(
+        &mut self,
+        client: &TileRequestClient,
+        download: RegionDownload,
+    ) -> RegionDownloadHandle {
+        let handle = RegionDownloadHandle {
+            id: self.next_id,
+            control: Arc::default(),
+        };
+        self.next_id += 1;
+
+        let mut progress = RegionDownloadProgress {
+            id: handle.id,
+            status: RegionDownloadStatus::Running,
+            done: 0,
+            total: download.tile_count(),
+            bytes: 0,
+            failed: 0,
+        };
+        // Every tile would fail or be skipped the same way, so the region
+        // isn't walked at all
+        match client.can_download(&download.layer) {
+            Ok(true) => {}
+            result => {
+                if let Err(err) = result {
+                    warn!("Failed to download region of {}: {}", download.layer, err);
+                    progress.failed = progress.total;
+                }
+                progress.done = progress.total;
+                progress.status = RegionDownloadStatus::Finished;
+                let _ = self.sender.send(progress);
+                return handle;
+            }
+        }
+
+        let client = client.clone();
+        let control = handle.control.clone();
+        let sender = self.sender.clone();
+        thread::Builder::new()
+            .name(format!("region-download-{}", handle.id))
+            .spawn(move || {
+                let min_interval = download
+                    .max_requests_per_second
+                    .filter(|rate| *rate > 0.0)
+                    .map(|rate| Duration::from_secs_f32(1.0 / rate));
+                let mut last_request: Option<Instant> = None;
+                let _ = sender.send(progress.clone());
+
+                for tile in download.tiles() {
+                    let running = control.wait_while_paused(|status| {
+                        let _ = sender.send(RegionDownloadProgress {
+                            status,
+                            ..progress.clone()
+                        });
+                    });
+                    if !running {
+                        progress.status = RegionDownloadStatus::Cancelled;
+                        let _ = sender.send(progress);
+                        return;
+                    }
+
+                    if let (Some(min_interval), Some(last_request)) = (min_interval, last_request) {
+                        thread::sleep(min_interval.saturating_sub(last_request.elapsed()));
+                    }
+                    let result = client.download_tile(
+                        &download.layer,
+                        tile.x as u64,
+                        tile.y as u64,
+                        tile.zoom as u64,
+                    );
+                    match result {
+                        // Tiles that were still fresh don't count towards the rate
+                        Ok(None) => {}
+                        Ok(Some(bytes)) => {
+                            progress.bytes += bytes;
+                            last_request = Some(Instant::now());
+                        }
+                        Err(err) => {
+                            warn!("Failed to download tile {:?}: {}", tile, err);
+                            progress.failed += 1;
+                            last_request = Some(Instant::now());
+                        }
+                    }
+                    progress.done += 1;
+                    let _ = sender.send(progress.clone());
+                }
+
+                progress.status = RegionDownloadStatus::Finished;
+                let _ = sender.send(progress);
+            })
+            .expect("Failed to spawn region download thread");
+        handle
+    }
+}
+
+pub fn send_region_download_progress(
+    downloads: Res<RegionDownloads>,
+    mut progress_writer: EventWriter<RegionDownloadProgress>,
+) {
+    progress_writer.write_batch(downloads.receiver.try_iter());
+}
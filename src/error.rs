@@ -0,0 +1,77 @@
+use std::{fmt, io};
+
+/// Everything that can go wrong while loading a single tile.
+#[derive(Debug)]
+pub enum TileError {
+    /// The request could not be sent or the response could not be read.
+    Network(ureq::Error),
+    /// The provider answered with a status other than 200.
+    HttpStatus(u16),
+    /// The raster tile could not be decoded.
+    Decode(image::ImageError),
+    /// The vector tile could not be parsed.
+    MvtParse(mvt_reader::error::ParserError),
+    /// Reading or writing the tile cache failed.
+    CacheIo(io::Error),
+    /// No enabled layer matches the requested provider.
+    NoProvider(String),
+    /// A local tile source, such as an MBTiles file, could not be read.
+    Source(Box<dyn std::error::Error + Send + Sync>),
+    /// The tile would have to be downloaded but the client is in cache-only mode.
+    Offline,
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::Network(err) => write!(f, "Network error: {}", err),
+            TileError::HttpStatus(status) => write!(f, "Tile server returned HTTP {}", status),
+            TileError::Decode(err) => write!(f, "Failed to decode tile: {}", err),
+            TileError::MvtParse(err) => write!(f, "Failed to parse vector tile: {}", err),
+            TileError::CacheIo(err) => write!(f, "Tile cache error: {}", err),
+            TileError::NoProvider(url) => write!(f, "No enabled tile provider for {}", url),
+            TileError::Source(err) => write!(f, "Failed to read tile source: {}", err),
+            TileError::Offline => write!(f, "Network access is disabled"),
+        }
+    }
+}
+
+impl std::error::Error for TileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TileError::Network(err) => Some(err),
+            TileError::Decode(err) => Some(err),
+            TileError::MvtParse(err) => Some(err),
+            TileError::CacheIo(err) => Some(err),
+            TileError::Source(err) => Some(err.as_ref()),
+            TileError::HttpStatus(_) | TileError::NoProvider(_) | TileError::Offline => None,
+        }
+    }
+}
+
+impl From<ureq::Error> for TileError {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::StatusCode(status) => TileError::HttpStatus(status),
+            err => TileError::Network(err),
+        }
+    }
+}
+
+impl From<image::ImageError> for TileError {
+    fn from(err: image::ImageError) -> Self {
+        TileError::Decode(err)
+    }
+}
+
+impl From<mvt_reader::error::ParserError> for TileError {
+    fn from(err: mvt_reader::error::ParserError) -> Self {
+        TileError::MvtParse(err)
+    }
+}
+
+impl From<io::Error> for TileError {
+    fn from(err: io::Error) -> Self {
+        TileError::CacheIo(err)
+    }
+}
@@ -4,20 +4,50 @@
 //! different tile providers (raster or vector).
 
 mod api;
+mod attribution;
+mod cache;
 mod camera;
+mod compression;
+mod download;
+mod error;
+mod provider;
+mod retry;
+mod sources;
 mod tile_map;
 mod types;
+mod wms;
+#[cfg(feature = "wmts")]
+mod wmts;
+mod worker;
 
 use bevy::prelude::*;
 use camera::camera_helper;
 
 /// Main plugin that combines all functionality
+///
+/// Start from [`MapViewerPlugin::default`] and override the fields you need
+/// with `..Default::default()`, so new settings don't break your code.
 pub struct MapViewerPlugin {
     pub starting_location: Coord,
     pub starting_zoom: u32,
     pub tile_quality: f32,
     pub cache_dir: String,
     pub starting_url: Option<String>,
+    /// Number of threads fetching and decoding tiles in the background.
+    pub worker_threads: usize,
+}
+
+impl Default for MapViewerPlugin {
+    fn default() -> Self {
+        Self {
+            starting_location: Coord::new(52.1951, 0.1313),
+            starting_zoom: 14,
+            tile_quality: 256.0,
+            cache_dir: "cache".to_string(),
+            starting_url: None,
+            worker_threads: worker::DEFAULT_WORKER_THREADS,
+        }
+    }
 }
 
 impl Plugin for MapViewerPlugin {
@@ -29,13 +59,33 @@ impl Plugin for MapViewerPlugin {
                 tile_quality: self.tile_quality,
                 cache_dir: self.cache_dir.clone(),
                 starting_url: self.starting_url.clone(),
+                worker_threads: self.worker_threads,
             });
     }
 }
 
 // Re-export important types so users don't need to import internal modules
+pub use api::NetworkMode;
+pub use attribution::MapAttribution;
+pub use cache::CacheLimits;
 pub use camera::camera_helper::CameraTrackingEvent;
+pub use download::{
+    RegionDownload, RegionDownloadHandle, RegionDownloadProgress, RegionDownloadStatus,
+    RegionDownloads,
+};
+pub use error::TileError;
+pub use provider::{ApiKey, Attribution, TileLayer, TileScheme};
+pub use retry::RetryPolicy;
+#[cfg(feature = "mbtiles")]
+pub use sources::mbtiles::{MbTiles, MbTilesMetadata};
+#[cfg(feature = "pmtiles")]
+pub use sources::pmtiles::{PmTiles, PmTilesCompression, PmTilesHeader};
+pub use sources::{ArchiveView, SourceTile, TileCompression, TileData, TileSource};
 pub use tile_map::TileMapPlugin;
 pub use types::*;
+pub use wms::{WmsLayer, WmsVersion};
+#[cfg(feature = "wmts")]
+pub use wmts::{WmtsCapabilities, WmtsLayer, WmtsResourceUrl, WmtsStyle, WmtsTileMatrixSet};
+pub use worker::TileWorkerPool;
 #[cfg(feature = "ui_blocking")]
 pub use camera_helper::EguiBlockInputState;
\ No newline at end of file
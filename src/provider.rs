@@ -0,0 +1,509 @@
+//! Tile providers and the layer stack they are drawn in.
+//!
+//! Providers are declared as URL templates whose placeholders are filled in
+//! for every tile request:
+//!
+//! - `{z}`, `{x}`, `{y}`: the tile coordinates. The y index is counted from
+//!   the top, or from the bottom for layers using the [`TileScheme::Tms`]
+//!   scheme.
+//! - `{-y}`: the y index counted from the bottom whatever the scheme, so
+//!   `{-y}` requests TMS tiles from an XYZ layer.
+//! - `{s}`: a subdomain from the layer's list, `a`, `b` and `c` by default.
+//!   The subdomain is picked from the tile coordinates, so a tile is always
+//!   requested from the same host and stays in that host's HTTP caches.
+//! - `{quadkey}`: the Bing style quadkey of the tile.
+//! - `{r}`: the retina suffix, `@2x` for layers that request high resolution
+//!   tiles and nothing otherwise.
+//! - `{bbox}`: the bounds of the tile in EPSG:3857 meters as
+//!   `minx,miny,maxx,maxy`, as used by WMS `GetMap` requests.
+
+use std::{fmt, sync::Arc};
+
+use crate::{sources::TileSource, TileType};
+
+const DEFAULT_SUBDOMAINS: [&str; 3] = ["a", "b", "c"];
+
+/// Fills in the placeholders of `template` for the given XYZ tile. `{s}` is
+/// taken from `subdomains`, or the default `a`, `b` and `c` if it is empty.
+/// Only `{y}` is written in `scheme`, the other placeholders are independent
+/// of it.
+pub fn build_tile_url(
+    template: &str,
+    subdomains: &[String],
+    retina: bool,
+    scheme: TileScheme,
+    x: u64,
+    y: u64,
+    zoom: u64,
+) -> String {
+    let subdomain = if subdomains.is_empty() {
+        DEFAULT_SUBDOMAINS[(x.wrapping_add(y) % DEFAULT_SUBDOMAINS.len() as u64) as usize]
+    } else {
+        subdomains[(x.wrapping_add(y) % subdomains.len() as u64) as usize].as_str()
+    };
+    let flipped_y = TileScheme::Tms.y(y, zoom);
+
+    template
+        .replace("{z}", &zoom.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &scheme.y(y, zoom).to_string())
+        .replace("{-y}", &flipped_y.to_string())
+        .replace("{s}", subdomain)
+        .replace("{quadkey}", &tile_to_quadkey(x, y, zoom))
+        .replace("{r}", if retina { "@2x" } else { "" })
+        .replace("{bbox}", &tile_to_bbox(x, y, zoom))
+}
+
+// The built-in providers were known by these origins before url templates,
+// and they are still accepted wherever a layer url is expected
+const LEGACY_ORIGINS: [(&str, &str); 5] = [
+    (
+        "https://tile.openstreetmap.org",
+        "https://tile.openstreetmap.org/{z}/{x}/{y}.png",
+    ),
+    (
+        "https://mt1.google.com/vt/lyrs=y",
+        "https://mt{s}.google.com/vt/lyrs=y&x={x}&y={y}&z={z}",
+    ),
+    (
+        "https://mt1.google.com/vt/lyrs=m",
+        "https://mt{s}.google.com/vt/lyrs=m&x={x}&y={y}&z={z}",
+    ),
+    (
+        "https://mt1.google.com/vt/lyrs=s",
+        "https://mt{s}.google.com/vt/lyrs=s&x={x}&y={y}&z={z}",
+    ),
+    (
+        "https://tiles.openfreemap.org/planet/20250122_001001_pt",
+        "https://tiles.openfreemap.org/planet/20250122_001001_pt/{z}/{x}/{y}.pbf",
+    ),
+];
+
+/// The template of the built-in provider formerly known by `origin`.
+pub fn template_for_legacy_origin(origin: &str) -> Option<&'static str> {
+    LEGACY_ORIGINS
+        .iter()
+        .find(|(legacy, _)| *legacy == origin.trim_end_matches('/'))
+        .map(|(_, template)| *template)
+}
+
+/// The origin a built-in provider was known by before url templates.
+pub fn legacy_origin(template: &str) -> Option<&'static str> {
+    LEGACY_ORIGINS
+        .iter()
+        .find(|(_, current)| *current == template)
+        .map(|(legacy, _)| *legacy)
+}
+
+/// Returns true if the url contains at least one tile placeholder.
+pub fn is_url_template(url: &str) -> bool {
+    ["{z}", "{x}", "{y}", "{-y}", "{quadkey}", "{bbox}"]
+        .iter()
+        .any(|placeholder| url.contains(placeholder))
+}
+
+/// The bounds of an XYZ tile in EPSG:3857 meters as `minx,miny,maxx,maxy`.
+// Computed in f64, `Tile::to_mercator` is only accurate to a few meters
+pub fn tile_to_bbox(x: u64, y: u64, zoom: u64) -> String {
+    const HALF_WORLD: f64 = 20037508.34;
+    let tile_width = HALF_WORLD * 2.0 / (1_u64 << zoom) as f64;
+    let min_x = -HALF_WORLD + x as f64 * tile_width;
+    let max_y = HALF_WORLD - y as f64 * tile_width;
+    format!(
+        "{:.2},{:.2},{:.2},{:.2}",
+        min_x,
+        max_y - tile_width,
+        min_x + tile_width,
+        max_y
+    )
+}
+
+// https://learn.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system
+pub fn tile_to_quadkey(x: u64, y: u64, zoom: u64) -> String {
+    let mut quadkey = String::with_capacity(zoom as usize);
+    for i in (1..=zoom).rev() {
+        let mask = 1 << (i - 1);
+        let mut digit = b'0';
+        if x & mask != 0 {
+            digit += 1;
+        }
+        if y & mask != 0 {
+            digit += 2;
+        }
+        quadkey.push(digit as char);
+    }
+    quadkey
+}
+
+/// A tile provider placed in the layer stack.
+///
+/// Layers are drawn from the lowest `z_order` to the highest, so an overlay
+/// such as a labels layer should be given a higher `z_order` than the imagery
+/// below it.
+#[derive(Clone)]
+pub struct TileLayer {
+    /// URL template of the provider, also used to identify the layer.
+    pub url: String,
+    pub tile_type: TileType,
+    /// Disabled layers are not fetched at all.
+    pub enabled: bool,
+    /// Hidden layers are still fetched but not drawn.
+    pub visible: bool,
+    pub z_order: i32,
+    pub opacity: f32,
+    /// Where the provider counts the y index from.
+    pub scheme: TileScheme,
+    /// Width and height in pixels of the textures the tiles are decoded to.
+    /// Tiles of another size are resampled. This is independent of
+    /// `tile_quality`, which sets the size of a tile on the map.
+    pub tile_size: u32,
+    /// Request high resolution tiles, expanding `{r}` to `@2x`.
+    pub retina: bool,
+    /// Hosts substituted for `{s}` in the url, `a`, `b` and `c` if empty.
+    pub subdomains: Vec<String>,
+    /// Extra headers sent with every request to the provider. A `User-Agent`
+    /// here replaces the one of the client.
+    pub headers: Vec<(String, String)>,
+    pub api_key: Option<ApiKey>,
+    /// Read tiles from this source instead of the url, which then only names
+    /// the layer.
+    pub source: Option<Arc<dyn TileSource>>,
+    /// Shown on the map while the layer is visible.
+    pub attribution: Option<Attribution>,
+}
+
+impl TileLayer {
+    pub fn new(url: String, tile_type: TileType) -> Self {
+        Self {
+            url,
+            tile_type,
+            enabled: true,
+            visible: true,
+            z_order: 0,
+            opacity: 1.0,
+            scheme: TileScheme::Xyz,
+            tile_size: 256,
+            retina: false,
+            subdomains: Vec::new(),
+            headers: Vec::new(),
+            api_key: None,
+            source: None,
+            attribution: None,
+        }
+    }
+
+    /// A layer showing the tiles of a custom source. `name` identifies the
+    /// layer in place of a url.
+    pub fn from_source(
+        name: String,
+        tile_type: TileType,
+        source: impl TileSource + 'static,
+    ) -> Self {
+        Self {
+            source: Some(Arc::new(source)),
+            ..Self::new(name, tile_type)
+        }
+    }
+
+    pub fn with_scheme(mut self, scheme: TileScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    /// Tiles are served at `tile_size` pixels, for example 512 for providers
+    /// with high resolution tiles.
+    pub fn with_tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = tile_size.max(1);
+        self
+    }
+
+    /// Requests `@2x` tiles, which are twice the usual 256 pixels.
+    pub fn with_retina(mut self) -> Self {
+        self.retina = true;
+        self.tile_size = 512;
+        self
+    }
+
+    pub fn with_subdomains<S: Into<String>>(
+        mut self,
+        subdomains: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.subdomains = subdomains.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_attribution(mut self, text: impl Into<String>, url: Option<&str>) -> Self {
+        self.attribution = Some(Attribution {
+            text: text.into(),
+            url: url.map(str::to_string),
+        });
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: ApiKey) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+}
+
+/// How a provider numbers the rows of tiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TileScheme {
+    /// Rows counted from the top, as used by most web maps.
+    #[default]
+    Xyz,
+    /// Rows counted from the bottom, as used by TMS endpoints such as
+    /// GeoServer's.
+    Tms,
+}
+
+impl TileScheme {
+    /// Converts the y index of an XYZ tile to the y index of this scheme.
+    pub fn y(self, y: u64, zoom: u64) -> u64 {
+        match self {
+            TileScheme::Xyz => y,
+            TileScheme::Tms => (1_u64 << zoom).saturating_sub(1).saturating_sub(y),
+        }
+    }
+}
+
+/// The credit a provider asks for, such as `© OpenStreetMap contributors`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribution {
+    pub text: String,
+    /// Where the terms or copyright notice of the provider can be found.
+    pub url: Option<String>,
+}
+
+/// A secret sent with every request to a provider.
+///
+/// Keys are added when the request is made rather than written into the url
+/// template, so they never end up in the tile cache, which is keyed by the
+/// template. The value is left out of the `Debug` output for the same reason.
+#[derive(Clone, PartialEq, Eq)]
+pub enum ApiKey {
+    /// Sent as a request header, for example `Authorization`.
+    Header { name: String, value: String },
+    /// Appended to the url as a query parameter, for example `?key=...`.
+    Query { name: String, value: String },
+}
+
+impl ApiKey {
+    pub fn header(name: impl Into<String>, value: impl Into<String>) -> Self {
+        ApiKey::Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn query(name: impl Into<String>, value: impl Into<String>) -> Self {
+        ApiKey::Query {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Adds the key to a tile url if it is a query parameter.
+    pub fn apply_to_url(&self, url: &mut String) {
+        if let ApiKey::Query { name, value } = self {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(&encode_query_value(name));
+            url.push('=');
+            url.push_str(&encode_query_value(value));
+        }
+    }
+}
+
+// Header values often carry credentials, so only their names are printed
+impl fmt::Debug for TileLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: Vec<(&str, &str)> = self
+            .headers
+            .iter()
+            .map(|(name, _)| (name.as_str(), "<redacted>"))
+            .collect();
+        f.debug_struct("TileLayer")
+            .field("url", &self.url)
+            .field("tile_type", &self.tile_type)
+            .field("enabled", &self.enabled)
+            .field("visible", &self.visible)
+            .field("z_order", &self.z_order)
+            .field("opacity", &self.opacity)
+            .field("scheme", &self.scheme)
+            .field("tile_size", &self.tile_size)
+            .field("retina", &self.retina)
+            .field("subdomains", &self.subdomains)
+            .field("headers", &headers)
+            .field("api_key", &self.api_key)
+            .field("source", &self.source)
+            .field("attribution", &self.attribution)
+            .finish()
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, name) = match self {
+            ApiKey::Header { name, .. } => ("Header", name),
+            ApiKey::Query { name, .. } => ("Query", name),
+        };
+        f.debug_struct(kind)
+            .field("name", name)
+            .field("value", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Percent-encodes a query parameter name or value. `:` is left as is, it is
+/// allowed in queries and WMS layer names often hold it for GeoServer
+/// workspaces.
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b':' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_bounds() {
+        assert_eq!(
+            tile_to_bbox(0, 0, 0),
+            "-20037508.34,-20037508.34,20037508.34,20037508.34"
+        );
+        assert_eq!(tile_to_bbox(0, 0, 1), "-20037508.34,0.00,0.00,20037508.34");
+        assert_eq!(tile_to_bbox(1, 1, 1), "0.00,-20037508.34,20037508.34,0.00");
+    }
+
+    #[test]
+    fn quadkeys() {
+        // The example of the Bing Maps tile system article
+        assert_eq!(tile_to_quadkey(3, 5, 3), "213");
+        assert_eq!(tile_to_quadkey(0, 0, 0), "");
+        assert_eq!(tile_to_quadkey(1, 1, 1), "3");
+    }
+
+    #[test]
+    fn templates_are_filled_in() {
+        let subdomains = ["0".to_string(), "1".to_string()];
+        assert_eq!(
+            build_tile_url(
+                "https://{s}.example.com/{z}/{x}/{y}{r}.png",
+                &[],
+                true,
+                TileScheme::Xyz,
+                1,
+                2,
+                3
+            ),
+            "https://a.example.com/3/1/2@2x.png"
+        );
+        assert_eq!(
+            build_tile_url(
+                "https://mt{s}.example.com/{quadkey}",
+                &subdomains,
+                false,
+                TileScheme::Xyz,
+                3,
+                5,
+                3
+            ),
+            "https://mt0.example.com/213"
+        );
+    }
+
+    #[test]
+    fn tms_rows_count_from_the_bottom() {
+        assert_eq!(TileScheme::Xyz.y(1, 2), 1);
+        assert_eq!(TileScheme::Tms.y(0, 0), 0);
+        assert_eq!(TileScheme::Tms.y(0, 2), 3);
+        assert_eq!(TileScheme::Tms.y(1, 2), 2);
+        assert_eq!(
+            build_tile_url("{z}/{x}/{-y}", &[], false, TileScheme::Xyz, 0, 0, 3),
+            "3/0/7"
+        );
+    }
+
+    #[test]
+    fn tms_layers_only_flip_y() {
+        let url = build_tile_url(
+            "{y}/{-y}/{quadkey}/{bbox}",
+            &[],
+            false,
+            TileScheme::Tms,
+            0,
+            0,
+            1,
+        );
+        // The top left tile, whose TMS row is 1
+        assert_eq!(url, "1/1/0/-20037508.34,0.00,0.00,20037508.34");
+        assert_eq!(
+            build_tile_url("{quadkey}", &[], false, TileScheme::Tms, 3, 5, 3),
+            "213"
+        );
+    }
+
+    #[test]
+    fn coordinates_past_the_world_do_not_overflow() {
+        let url = build_tile_url(
+            "https://{s}.example.com",
+            &[],
+            false,
+            TileScheme::Xyz,
+            u64::MAX,
+            1,
+            1,
+        );
+        assert_eq!(url, "https://a.example.com");
+    }
+
+    #[test]
+    fn query_api_keys_are_encoded() {
+        let mut url = "https://example.com/{z}/{x}/{y}.png?style=dark".to_string();
+        ApiKey::query("api key", "a+b&c=d#e").apply_to_url(&mut url);
+        assert_eq!(
+            url,
+            "https://example.com/{z}/{x}/{y}.png?style=dark&api%20key=a%2Bb%26c%3Dd%23e"
+        );
+    }
+
+    #[test]
+    fn debug_output_hides_secrets() {
+        let layer = TileLayer::new(
+            "https://example.com/{z}/{x}/{y}.png".to_string(),
+            TileType::Raster,
+        )
+        .with_header("Authorization", "Bearer secret-token")
+        .with_api_key(ApiKey::query("key", "secret-key"));
+        let debug = format!("{:?}", layer);
+        assert!(debug.contains("Authorization"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn legacy_origins_find_their_template() {
+        assert_eq!(
+            template_for_legacy_origin("https://mt1.google.com/vt/lyrs=y"),
+            Some("https://mt{s}.google.com/vt/lyrs=y&x={x}&y={y}&z={z}")
+        );
+        assert_eq!(
+            template_for_legacy_origin("https://tile.openstreetmap.org/"),
+            Some("https://tile.openstreetmap.org/{z}/{x}/{y}.png")
+        );
+        assert_eq!(template_for_legacy_origin("https://example.com"), None);
+    }
+}
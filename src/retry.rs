@@ -0,0 +1,161 @@
+//! How failed tile requests are retried.
+
+use std::time::{Duration, SystemTime};
+
+use ureq::http::HeaderMap;
+
+use crate::error::TileError;
+
+/// Retries with exponential backoff. The delay before retry `n` is
+/// `initial_backoff * 2^(n - 1)`, capped at `max_backoff`, of which a random
+/// part is skipped so that the workers don't all retry at the same moment.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts including the first request, 1 disables retries.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction of each delay, between 0 and 1, that is randomized.
+    pub jitter: f32,
+    /// HTTP status codes that are retried.
+    pub retry_statuses: Vec<u16>,
+    /// Retry timeouts and dropped connections.
+    pub retry_network_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.5,
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+            retry_network_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before retrying after the given failed attempt, or `None` if
+    /// the request should not be retried.
+    ///
+    /// A `Retry-After` from the server replaces the backoff. If it asks for a
+    /// longer wait than `max_backoff` the request is given up instead of
+    /// holding a worker for that long.
+    pub fn retry_delay(
+        &self,
+        attempt: u32,
+        error: &TileError,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.is_retryable(error) {
+            return None;
+        }
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= self.max_backoff).then_some(retry_after);
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        Some(backoff.mul_f32(1.0 - jitter * fastrand::f32()))
+    }
+
+    fn is_retryable(&self, error: &TileError) -> bool {
+        match error {
+            TileError::HttpStatus(status) => self.retry_statuses.contains(status),
+            TileError::Network(
+                ureq::Error::Io(_) | ureq::Error::Timeout(_) | ureq::Error::ConnectionFailed,
+            ) => self.retry_network_errors,
+            _ => false,
+        }
+    }
+}
+
+/// Reads a `Retry-After` header given either in seconds or as an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("retry-after")?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let error = TileError::HttpStatus(503);
+        assert_eq!(
+            policy.retry_delay(1, &error, None),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            policy.retry_delay(3, &error, None),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy.retry_delay(9, &error, None),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(policy.retry_delay(10, &error, None), None);
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = RetryPolicy::default();
+        let delay = policy
+            .retry_delay(2, &TileError::HttpStatus(429), None)
+            .unwrap();
+        assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_after_replaces_the_backoff() {
+        let policy = RetryPolicy::default();
+        let error = TileError::HttpStatus(429);
+        assert_eq!(
+            policy.retry_delay(1, &error, Some(Duration::from_secs(5))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            policy.retry_delay(1, &error, Some(Duration::from_secs(60))),
+            None
+        );
+    }
+
+    #[test]
+    fn only_retryable_errors_are_retried() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.retry_delay(1, &TileError::HttpStatus(404), None),
+            None
+        );
+        assert_eq!(policy.retry_delay(1, &TileError::Offline, None), None);
+        assert_eq!(
+            RetryPolicy::no_retries().retry_delay(1, &TileError::HttpStatus(503), None),
+            None
+        );
+    }
+}
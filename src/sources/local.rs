@@ -0,0 +1,95 @@
+//! Tiles stored as plain files, such as a gdal2tiles or Maperitive export.
+//!
+//! The layer url is either a path template like
+//! `file:///data/tiles/{z}/{x}/{y}.png` or just the directory holding the zoom
+//! levels. TMS layouts are read by setting the scheme of the layer, and a bare
+//! directory is always read as TMS if gdal2tiles left its
+//! `tilemapresource.xml` in it.
+
+use std::{fs, io, path::Path};
+
+use crate::{
+    error::TileError,
+    provider::{build_tile_url, is_url_template, TileScheme},
+    sources::{content_type_for_format, SourceTile, TileData, TileSource},
+    TileType,
+};
+
+#[derive(Debug, Clone)]
+pub struct LocalTiles {
+    template: String,
+    scheme: TileScheme,
+}
+
+impl LocalTiles {
+    /// Returns the source if `url` is a `file://` url or the path of an
+    /// existing directory, so that a url missing its scheme is not mistaken
+    /// for one.
+    pub fn from_url(url: &str, tile_type: TileType, scheme: TileScheme) -> Option<Self> {
+        let path = match url.strip_prefix("file://") {
+            Some(path) => path,
+            None if url.contains("://") => return None,
+            None if !Path::new(static_prefix(url)).is_dir() => return None,
+            None => url,
+        };
+
+        if is_url_template(path) {
+            return Some(Self {
+                template: path.to_string(),
+                scheme,
+            });
+        }
+
+        let scheme = if Path::new(path).join("tilemapresource.xml").exists() {
+            TileScheme::Tms
+        } else {
+            scheme
+        };
+        let extension = match tile_type {
+            TileType::Raster => "png",
+            TileType::Vector => "pbf",
+        };
+        Some(Self {
+            template: format!(
+                "{}/{{z}}/{{x}}/{{y}}.{}",
+                path.trim_end_matches(['/', '\\']),
+                extension
+            ),
+            scheme,
+        })
+    }
+
+    /// Reads the tile file, `None` if there is no file for the tile.
+    pub fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<Vec<u8>>, TileError> {
+        let path = build_tile_url(&self.template, &[], false, self.scheme, x, y, zoom);
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(TileError::Source(Box::new(err))),
+        }
+    }
+}
+
+// The directory part of a path template, before the first placeholder
+fn static_prefix(template: &str) -> &str {
+    let prefix = &template[..template.find('{').unwrap_or(template.len())];
+    match prefix.rfind(['/', '\\']) {
+        Some(end) if template.contains('{') => &prefix[..=end],
+        _ => prefix,
+    }
+}
+
+impl TileSource for LocalTiles {
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<SourceTile>, TileError> {
+        // The extension of the template names the format of every tile
+        let content_type = Path::new(&self.template)
+            .extension()
+            .and_then(|extension| content_type_for_format(&extension.to_string_lossy()));
+        Ok(
+            LocalTiles::get_tile(self, x, y, zoom)?.map(|bytes| SourceTile {
+                content_type: content_type.map(str::to_string),
+                ..SourceTile::new(TileData::Encoded(bytes))
+            }),
+        )
+    }
+}
@@ -0,0 +1,231 @@
+//! [MBTiles](https://github.com/mapbox/mbtiles-spec) archives.
+//!
+//! MBTiles stores tiles in a SQLite database using the TMS tile scheme, so the
+//! y index is flipped when looking tiles up.
+
+use std::{
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+
+use crate::{
+    error::TileError,
+    provider::TileScheme,
+    sources::{content_type_for_format, SourceTile, TileData, TileSource},
+    Coord, Tile, TileType,
+};
+
+/// The parts of the `metadata` table used by the viewer.
+#[derive(Debug, Clone, Default)]
+pub struct MbTilesMetadata {
+    pub name: Option<String>,
+    /// Tile format, `png`, `jpg`, `webp` or `pbf`.
+    pub format: Option<String>,
+    /// Bounds as `[west, south, east, north]` in degrees.
+    pub bounds: Option<[f32; 4]>,
+    /// Default view, the `center` entry is given as `longitude,latitude,zoom`.
+    pub center: Option<Coord>,
+    pub center_zoom: Option<u32>,
+    pub min_zoom: Option<u32>,
+    pub max_zoom: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct MbTiles {
+    // rusqlite connections can be sent between threads but not shared
+    connection: Mutex<Connection>,
+    metadata: MbTilesMetadata,
+}
+
+impl MbTiles {
+    pub fn open(path: &Path) -> Result<Self, TileError> {
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(source_error)?;
+        Self::from_connection(connection)
+    }
+
+    fn from_connection(connection: Connection) -> Result<Self, TileError> {
+        let metadata = read_metadata(&connection).map_err(source_error)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            metadata,
+        })
+    }
+
+    pub fn metadata(&self) -> &MbTilesMetadata {
+        &self.metadata
+    }
+
+    /// The tile type given by the archive format, if it has one.
+    pub fn tile_type(&self) -> Option<TileType> {
+        match self.metadata.format.as_deref()? {
+            "pbf" | "mvt" => Some(TileType::Vector),
+            _ => Some(TileType::Raster),
+        }
+    }
+
+    /// Reads the tile at the XYZ coordinates, `None` if the archive has no
+    /// tile there.
+    pub fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<Vec<u8>>, TileError> {
+        if !self.covers(x, y, zoom) {
+            return Ok(None);
+        }
+
+        let tms_y = TileScheme::Tms.y(y, zoom);
+        let connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        connection
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                (zoom, x, tms_y),
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map_err(source_error)
+    }
+
+    // Skips the query for tiles outside of the zoom range and bounds
+    fn covers(&self, x: u64, y: u64, zoom: u64) -> bool {
+        let zoom = zoom as u32;
+        if self.metadata.min_zoom.is_some_and(|min| zoom < min)
+            || self.metadata.max_zoom.is_some_and(|max| zoom > max)
+        {
+            return false;
+        }
+        let Some([west, south, east, north]) = self.metadata.bounds else {
+            return true;
+        };
+        let top_left = Tile::new(x as i32, y as i32, zoom).to_lat_long();
+        let bottom_right = Tile::new(x as i32 + 1, y as i32 + 1, zoom).to_lat_long();
+        // The right edge of the last column wraps around to -180
+        let right = if bottom_right.long <= top_left.long {
+            180.0
+        } else {
+            bottom_right.long
+        };
+        top_left.long <= east && right >= west && bottom_right.lat <= north && top_left.lat >= south
+    }
+}
+
+impl TileSource for MbTiles {
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<SourceTile>, TileError> {
+        let content_type = self
+            .metadata
+            .format
+            .as_deref()
+            .and_then(content_type_for_format);
+        Ok(
+            MbTiles::get_tile(self, x, y, zoom)?.map(|bytes| SourceTile {
+                content_type: content_type.map(str::to_string),
+                ..SourceTile::new(TileData::Encoded(bytes))
+            }),
+        )
+    }
+
+    fn tile_type(&self) -> Option<TileType> {
+        MbTiles::tile_type(self)
+    }
+}
+
+fn read_metadata(connection: &Connection) -> rusqlite::Result<MbTilesMetadata> {
+    let mut metadata = MbTilesMetadata::default();
+    let mut statement = connection.prepare("SELECT name, value FROM metadata")?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (name, value) = row?;
+        match name.as_str() {
+            "name" => metadata.name = Some(value),
+            "format" => metadata.format = Some(value.to_ascii_lowercase()),
+            "minzoom" => metadata.min_zoom = value.trim().parse().ok(),
+            "maxzoom" => metadata.max_zoom = value.trim().parse().ok(),
+            "bounds" => {
+                let bounds: Vec<f32> = value
+                    .split(',')
+                    .filter_map(|part| part.trim().parse().ok())
+                    .collect();
+                if let [west, south, east, north] = bounds[..] {
+                    metadata.bounds = Some([west, south, east, north]);
+                }
+            }
+            "center" => {
+                let parts: Vec<&str> = value.split(',').map(str::trim).collect();
+                if let [long, lat, rest @ ..] = &parts[..] {
+                    if let (Ok(long), Ok(lat)) = (long.parse(), lat.parse()) {
+                        metadata.center = Some(Coord::new(lat, long));
+                    }
+                    metadata.center_zoom = rest.first().and_then(|zoom| zoom.parse().ok());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+fn source_error(err: rusqlite::Error) -> TileError {
+    TileError::Source(Box::new(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 2x2 tile archive at zoom 1 covering the northern hemisphere
+    fn fixture() -> MbTiles {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE metadata (name TEXT, value TEXT);
+                 CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+                 INSERT INTO metadata VALUES
+                     ('name', 'fixture'), ('format', 'PNG'), ('bounds', '-180, 1, 180, 85'),
+                     ('center', '10.5,20.25,1'), ('minzoom', '1'), ('maxzoom', ' 2 ');
+                 INSERT INTO tiles VALUES (1, 0, 0, x'00'), (1, 0, 1, x'01');",
+            )
+            .unwrap();
+        MbTiles::from_connection(connection).unwrap()
+    }
+
+    #[test]
+    fn metadata_is_parsed() {
+        let archive = fixture();
+        let metadata = archive.metadata();
+        assert_eq!(metadata.name.as_deref(), Some("fixture"));
+        assert_eq!(metadata.format.as_deref(), Some("png"));
+        assert_eq!(metadata.bounds, Some([-180.0, 1.0, 180.0, 85.0]));
+        assert_eq!(metadata.center, Some(Coord::new(20.25, 10.5)));
+        assert_eq!(metadata.center_zoom, Some(1));
+        assert_eq!(metadata.min_zoom, Some(1));
+        assert_eq!(metadata.max_zoom, Some(2));
+        assert_eq!(archive.tile_type(), Some(TileType::Raster));
+    }
+
+    #[test]
+    fn rows_are_flipped_to_tms() {
+        let archive = fixture();
+        // XYZ row 0 is the top, stored as TMS row 1
+        assert_eq!(archive.get_tile(0, 0, 1).unwrap(), Some(vec![1]));
+        assert_eq!(archive.get_tile(1, 0, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn tiles_outside_the_archive_are_not_covered() {
+        let archive = fixture();
+        assert!(archive.covers(0, 0, 1));
+        assert!(archive.covers(1, 0, 1));
+        // The southern hemisphere and zoom levels outside 1..=2
+        assert!(!archive.covers(0, 1, 1));
+        assert!(!archive.covers(0, 0, 0));
+        assert!(!archive.covers(0, 0, 3));
+        assert_eq!(archive.get_tile(0, 1, 1).unwrap(), None);
+    }
+}
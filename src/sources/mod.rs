@@ -0,0 +1,204 @@
+//! Tile sources that are read from somewhere other than a tile server.
+//!
+//! A layer is served from a local archive when its url points at one, for
+//! example `mbtiles:///data/region.mbtiles` or just `/data/region.pmtiles`.
+//! Any other `file://` url, or the path of an existing directory, is read as
+//! a directory of tiles.
+//!
+//! Other backends, such as procedurally generated tiles or tiles kept in a
+//! database, implement [`TileSource`] and are set as the source of a layer.
+
+pub mod local;
+#[cfg(feature = "mbtiles")]
+pub mod mbtiles;
+#[cfg(feature = "pmtiles")]
+pub mod pmtiles;
+
+#[cfg(any(feature = "mbtiles", feature = "pmtiles"))]
+use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{error::TileError, provider::TileScheme, Coord, TileType};
+
+/// A backend that tiles are read from.
+///
+/// Sources are called from the tile worker threads, possibly from several at
+/// once, so any state they keep has to be shared safely. Tiles returned by a
+/// source are kept in the memory cache but not in the disk cache.
+pub trait TileSource: Send + Sync + Debug {
+    /// Returns the tile at the XYZ coordinates, `None` if the source has no
+    /// tile there. Sources that know nothing but the data can return
+    /// `data.into()`.
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<SourceTile>, TileError>;
+
+    /// How encoded tiles are decoded, the tile type of the layer if `None`.
+    fn tile_type(&self) -> Option<TileType> {
+        None
+    }
+
+    /// Where to start the map and how far it can be zoomed, if the source
+    /// knows.
+    fn view(&self) -> Option<ArchiveView> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TileData {
+    /// An encoded image, such as a PNG, or a Mapbox vector tile.
+    Encoded(Vec<u8>),
+    /// Already decoded pixels, `size * size` RGBA values.
+    Rgba { pixels: Vec<u8>, size: u32 },
+}
+
+/// A tile returned by a [`TileSource`], with what the source knows about it.
+#[derive(Debug, Clone)]
+pub struct SourceTile {
+    pub data: TileData,
+    /// MIME type of encoded data, such as `image/png`. The image format is
+    /// guessed from the bytes if `None`.
+    pub content_type: Option<String>,
+    /// How encoded vector tiles are compressed, detected from the bytes if
+    /// `None`.
+    pub compression: Option<TileCompression>,
+    /// Unix time in seconds after which the tile is read from the source
+    /// again. Without an expiry the tile stays in the memory cache until it is
+    /// evicted.
+    pub expires: Option<u64>,
+}
+
+impl SourceTile {
+    pub fn new(data: TileData) -> Self {
+        Self {
+            data,
+            content_type: None,
+            compression: None,
+            expires: None,
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_compression(mut self, compression: TileCompression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_expires(mut self, expires: u64) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+}
+
+impl From<TileData> for SourceTile {
+    fn from(data: TileData) -> Self {
+        Self::new(data)
+    }
+}
+
+/// Compression of an encoded tile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileCompression {
+    None,
+    Gzip,
+    Zlib,
+    Brotli,
+}
+
+/// The MIME type of tiles stored with the file extension or archive format
+/// `format`, if it is one the viewer can draw.
+pub fn content_type_for_format(format: &str) -> Option<&'static str> {
+    match format.to_ascii_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "pbf" | "mvt" => Some("application/vnd.mapbox-vector-tile"),
+        _ => None,
+    }
+}
+
+/// Where to start the map and how far it can be zoomed, as stored in an archive.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveView {
+    pub center: Coord,
+    pub zoom: u32,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+}
+
+/// Returns the path of the archive if `url` refers to a local file with the
+/// given `extension`, either through a `{extension}://` url or a plain path.
+#[cfg(any(feature = "mbtiles", feature = "pmtiles"))]
+pub fn archive_path(url: &str, extension: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix(&format!("{}://", extension)) {
+        return Some(PathBuf::from(path));
+    }
+    let path = url.strip_prefix("file://").unwrap_or(url);
+    if !path.contains("://") && path.ends_with(&format!(".{}", extension)) {
+        return Some(PathBuf::from(path));
+    }
+    None
+}
+
+/// Archives opened by the client and the local directories layers resolved
+/// to, shared by all of its clones so that each is only opened once.
+#[derive(Debug, Clone, Default)]
+pub struct OpenSources {
+    // By url, tile type and scheme, `None` for urls that aren't local
+    local: Arc<Mutex<HashMap<LocalKey, Option<Arc<local::LocalTiles>>>>>,
+    #[cfg(feature = "mbtiles")]
+    mbtiles: Arc<Mutex<HashMap<PathBuf, Arc<mbtiles::MbTiles>>>>,
+    #[cfg(feature = "pmtiles")]
+    pmtiles: Arc<Mutex<HashMap<PathBuf, Arc<pmtiles::PmTiles>>>>,
+}
+
+type LocalKey = (String, TileType, TileScheme);
+
+impl OpenSources {
+    /// The directory of tiles `url` points at, if any.
+    pub fn local(
+        &self,
+        url: &str,
+        tile_type: TileType,
+        scheme: TileScheme,
+    ) -> Option<Arc<local::LocalTiles>> {
+        let key = (url.to_string(), tile_type, scheme);
+        let mut local = self.local.lock().unwrap_or_else(PoisonError::into_inner);
+        local
+            .entry(key)
+            .or_insert_with(|| local::LocalTiles::from_url(url, tile_type, scheme).map(Arc::new))
+            .clone()
+    }
+
+    #[cfg(feature = "mbtiles")]
+    pub fn mbtiles(&self, path: PathBuf) -> Result<Arc<mbtiles::MbTiles>, TileError> {
+        open_once(&self.mbtiles, path, mbtiles::MbTiles::open)
+    }
+
+    #[cfg(feature = "pmtiles")]
+    pub fn pmtiles(&self, path: PathBuf) -> Result<Arc<pmtiles::PmTiles>, TileError> {
+        open_once(&self.pmtiles, path, pmtiles::PmTiles::open)
+    }
+}
+
+#[cfg(any(feature = "mbtiles", feature = "pmtiles"))]
+fn open_once<T>(
+    opened: &Mutex<HashMap<PathBuf, Arc<T>>>,
+    path: PathBuf,
+    open: impl FnOnce(&std::path::Path) -> Result<T, TileError>,
+) -> Result<Arc<T>, TileError> {
+    let mut opened = opened.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(archive) = opened.get(&path) {
+        return Ok(archive.clone());
+    }
+    let archive = Arc::new(open(&path)?);
+    opened.insert(path, archive.clone());
+    Ok(archive)
+}
@@ -0,0 +1,482 @@
+//! [PMTiles v3](https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md) archives.
+//!
+//! Tiles are addressed by a tile id along a Hilbert curve and located through
+//! a root directory and, for larger archives, leaf directories. Directories
+//! are parsed lazily and kept in memory once read.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    compression::{brotli, gunzip},
+    error::TileError,
+    sources::{ArchiveView, SourceTile, TileCompression, TileData, TileSource},
+    Coord, TileType,
+};
+
+const HEADER_LEN: usize = 127;
+// The spec allows the root directory and at most two levels of leaves
+const MAX_DIRECTORY_DEPTH: usize = 3;
+const MAX_CACHED_LEAVES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmTilesCompression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl From<u8> for PmTilesCompression {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PmTilesCompression::None,
+            2 => PmTilesCompression::Gzip,
+            3 => PmTilesCompression::Brotli,
+            4 => PmTilesCompression::Zstd,
+            _ => PmTilesCompression::Unknown,
+        }
+    }
+}
+
+/// The fixed size header at the start of every archive.
+#[derive(Debug, Clone)]
+pub struct PmTilesHeader {
+    root_directory: (u64, u64),
+    leaf_directories_offset: u64,
+    tile_data_offset: u64,
+    pub internal_compression: PmTilesCompression,
+    pub tile_compression: PmTilesCompression,
+    /// `None` for the tile types the viewer can't draw.
+    pub tile_type: Option<TileType>,
+    /// MIME type of the tiles, `None` if the archive doesn't say.
+    pub content_type: Option<&'static str>,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    /// Bounds as `[west, south, east, north]` in degrees.
+    pub bounds: [f32; 4],
+    pub center: Coord,
+    pub center_zoom: u32,
+}
+
+impl PmTilesHeader {
+    fn parse(bytes: &[u8]) -> Result<Self, TileError> {
+        if bytes.len() < HEADER_LEN || &bytes[0..7] != b"PMTiles" {
+            return Err(archive_error("Not a PMTiles archive"));
+        }
+        if bytes[7] != 3 {
+            return Err(archive_error(format!(
+                "Unsupported PMTiles version {}",
+                bytes[7]
+            )));
+        }
+
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let degrees_at =
+            |at: usize| i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as f32 / 1e7;
+        Ok(Self {
+            root_directory: (u64_at(8), u64_at(16)),
+            leaf_directories_offset: u64_at(40),
+            tile_data_offset: u64_at(56),
+            internal_compression: bytes[97].into(),
+            tile_compression: bytes[98].into(),
+            tile_type: match bytes[99] {
+                1 => Some(TileType::Vector),
+                2..=4 => Some(TileType::Raster),
+                _ => None,
+            },
+            content_type: match bytes[99] {
+                1 => Some("application/vnd.mapbox-vector-tile"),
+                2 => Some("image/png"),
+                3 => Some("image/jpeg"),
+                4 => Some("image/webp"),
+                5 => Some("image/avif"),
+                _ => None,
+            },
+            min_zoom: bytes[100] as u32,
+            max_zoom: bytes[101] as u32,
+            bounds: [
+                degrees_at(102),
+                degrees_at(106),
+                degrees_at(110),
+                degrees_at(114),
+            ],
+            center_zoom: bytes[118] as u32,
+            center: Coord::new(degrees_at(123), degrees_at(119)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DirectoryEntry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    // 0 for entries pointing at a leaf directory
+    run_length: u64,
+}
+
+#[derive(Debug)]
+pub struct PmTiles {
+    file: Mutex<File>,
+    header: PmTilesHeader,
+    root: Arc<Vec<DirectoryEntry>>,
+    // Leaf directories by offset
+    leaves: Mutex<HashMap<u64, Arc<Vec<DirectoryEntry>>>>,
+}
+
+impl PmTiles {
+    pub fn open(path: &Path) -> Result<Self, TileError> {
+        let mut file = File::open(path).map_err(|err| TileError::Source(Box::new(err)))?;
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)
+            .map_err(|err| TileError::Source(Box::new(err)))?;
+        let header = PmTilesHeader::parse(&header)?;
+
+        let mut archive = Self {
+            file: Mutex::new(file),
+            header,
+            root: Arc::default(),
+            leaves: Mutex::default(),
+        };
+        let (offset, length) = archive.header.root_directory;
+        archive.root = Arc::new(archive.read_directory(offset, length)?);
+        Ok(archive)
+    }
+
+    pub fn header(&self) -> &PmTilesHeader {
+        &self.header
+    }
+
+    /// Reads the tile at the XYZ coordinates, `None` if the archive has no
+    /// tile there. Gzip compressed tiles are decompressed.
+    pub fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<Vec<u8>>, TileError> {
+        let zoom_range = self.header.min_zoom as u64..=self.header.max_zoom as u64;
+        if !zoom_range.contains(&zoom) || x >> zoom != 0 || y >> zoom != 0 {
+            return Ok(None);
+        }
+        let tile_id = zxy_to_tile_id(zoom, x, y);
+
+        let mut directory = self.root.clone();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let Some(entry) = find_entry(&directory, tile_id) else {
+                return Ok(None);
+            };
+            if entry.run_length > 0 {
+                let bytes =
+                    self.read_bytes(self.header.tile_data_offset + entry.offset, entry.length)?;
+                return decompress(bytes, self.header.tile_compression).map(Some);
+            }
+            directory = self.leaf_directory(entry.offset, entry.length)?;
+        }
+        Err(archive_error("PMTiles directories are nested too deeply"))
+    }
+
+    fn leaf_directory(
+        &self,
+        offset: u64,
+        length: u64,
+    ) -> Result<Arc<Vec<DirectoryEntry>>, TileError> {
+        let mut leaves = self.leaves.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(leaf) = leaves.get(&offset) {
+            return Ok(leaf.clone());
+        }
+        let leaf =
+            Arc::new(self.read_directory(self.header.leaf_directories_offset + offset, length)?);
+        if leaves.len() >= MAX_CACHED_LEAVES {
+            leaves.clear();
+        }
+        leaves.insert(offset, leaf.clone());
+        Ok(leaf)
+    }
+
+    fn read_directory(&self, offset: u64, length: u64) -> Result<Vec<DirectoryEntry>, TileError> {
+        let bytes = self.read_bytes(offset, length)?;
+        let bytes = decompress(bytes, self.header.internal_compression)?;
+        parse_directory(&bytes)
+    }
+
+    fn read_bytes(&self, offset: u64, length: u64) -> Result<Vec<u8>, TileError> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut bytes = vec![0; length as usize];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut bytes))
+            .map_err(|err| TileError::Source(Box::new(err)))?;
+        Ok(bytes)
+    }
+}
+
+impl TileSource for PmTiles {
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Option<SourceTile>, TileError> {
+        // Tiles are already decompressed
+        Ok(
+            PmTiles::get_tile(self, x, y, zoom)?.map(|bytes| SourceTile {
+                content_type: self.header.content_type.map(str::to_string),
+                ..SourceTile::new(TileData::Encoded(bytes)).with_compression(TileCompression::None)
+            }),
+        )
+    }
+
+    fn tile_type(&self) -> Option<TileType> {
+        self.header.tile_type
+    }
+
+    fn view(&self) -> Option<ArchiveView> {
+        let header = &self.header;
+        let max_zoom = header.max_zoom.max(header.min_zoom);
+        Some(ArchiveView {
+            center: header.center,
+            zoom: header.center_zoom.clamp(header.min_zoom, max_zoom),
+            min_zoom: header.min_zoom,
+            max_zoom,
+        })
+    }
+}
+
+fn decompress(bytes: Vec<u8>, compression: PmTilesCompression) -> Result<Vec<u8>, TileError> {
+    match compression {
+        PmTilesCompression::None | PmTilesCompression::Unknown => Ok(bytes),
+        PmTilesCompression::Gzip => gunzip(&bytes),
+        PmTilesCompression::Brotli => brotli(&bytes),
+        compression => Err(archive_error(format!(
+            "Unsupported PMTiles compression {:?}",
+            compression
+        ))),
+    }
+}
+
+fn parse_directory(mut bytes: &[u8]) -> Result<Vec<DirectoryEntry>, TileError> {
+    let count = read_varint(&mut bytes)? as usize;
+    let mut entries = vec![
+        DirectoryEntry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count
+    ];
+
+    // Columns are stored one after the other, tile ids as deltas
+    let mut last_id = 0;
+    for entry in entries.iter_mut() {
+        last_id += read_varint(&mut bytes)?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(&mut bytes)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(&mut bytes)?;
+    }
+    // An offset of 0 means the entry directly follows the previous one
+    for i in 0..count {
+        let value = read_varint(&mut bytes)?;
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1].offset + entries[i - 1].length
+        } else {
+            value.saturating_sub(1)
+        };
+    }
+    Ok(entries)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, TileError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| archive_error("Truncated PMTiles directory"))?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(archive_error("Invalid varint in PMTiles directory"))
+}
+
+// The entry with the largest tile id not above `tile_id`, if it covers it
+fn find_entry(entries: &[DirectoryEntry], tile_id: u64) -> Option<DirectoryEntry> {
+    let index = match entries.binary_search_by_key(&tile_id, |entry| entry.tile_id) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let entry = entries[index];
+    if entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+// Tiles of all lower zoom levels come first, then the position on the Hilbert
+// curve covering the zoom level
+fn zxy_to_tile_id(zoom: u64, x: u64, y: u64) -> u64 {
+    let lower_levels = ((1_u64 << (2 * zoom)) - 1) / 3;
+    let n = 1_u64 << zoom;
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u64;
+        let ry = ((y & s) > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    lower_levels + d
+}
+
+fn archive_error(message: impl Into<String>) -> TileError {
+    TileError::Source(message.into().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(tile_id: u64, offset: u64, length: u64, run_length: u64) -> DirectoryEntry {
+        DirectoryEntry {
+            tile_id,
+            offset,
+            length,
+            run_length,
+        }
+    }
+
+    fn write_varint(mut value: u64, bytes: &mut Vec<u8>) {
+        while value >= 0x80 {
+            bytes.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+    }
+
+    // Serializes entries the way the spec lays them out, using offset 0 for
+    // entries that directly follow the previous one
+    fn write_directory(entries: &[DirectoryEntry]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(entries.len() as u64, &mut bytes);
+        let mut last_id = 0;
+        for entry in entries {
+            write_varint(entry.tile_id - last_id, &mut bytes);
+            last_id = entry.tile_id;
+        }
+        for entry in entries {
+            write_varint(entry.run_length, &mut bytes);
+        }
+        for entry in entries {
+            write_varint(entry.length, &mut bytes);
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            let contiguous = i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length;
+            write_varint(if contiguous { 0 } else { entry.offset + 1 }, &mut bytes);
+        }
+        bytes
+    }
+
+    #[test]
+    fn tile_ids_match_the_spec() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(2, 3, 0), 20);
+        assert_eq!(zxy_to_tile_id(12, 3423, 1763), 19078479);
+    }
+
+    #[test]
+    fn varints() {
+        let mut bytes: &[u8] = &[0x01, 0xac, 0x02, 0x80];
+        assert_eq!(read_varint(&mut bytes).unwrap(), 1);
+        assert_eq!(read_varint(&mut bytes).unwrap(), 300);
+        assert!(read_varint(&mut bytes).is_err());
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let entries = [
+            entry(0, 0, 100, 1),
+            entry(1, 100, 50, 3),
+            entry(10, 10_000, 20, 1),
+            entry(11, 10_020, 400, 0),
+        ];
+        let parsed = parse_directory(&write_directory(&entries)).unwrap();
+        assert_eq!(parsed.len(), entries.len());
+        for (parsed, expected) in parsed.iter().zip(&entries) {
+            assert_eq!(parsed.tile_id, expected.tile_id);
+            assert_eq!(parsed.offset, expected.offset);
+            assert_eq!(parsed.length, expected.length);
+            assert_eq!(parsed.run_length, expected.run_length);
+        }
+    }
+
+    #[test]
+    fn entries_cover_their_run() {
+        let entries = [
+            entry(1, 0, 10, 3),
+            entry(10, 10, 10, 1),
+            entry(20, 0, 500, 0),
+        ];
+        assert!(find_entry(&entries, 0).is_none());
+        assert_eq!(find_entry(&entries, 3).unwrap().tile_id, 1);
+        assert!(find_entry(&entries, 4).is_none());
+        assert_eq!(find_entry(&entries, 10).unwrap().tile_id, 10);
+        // Leaf directories cover every id up to the next entry
+        assert_eq!(find_entry(&entries, 1000).unwrap().run_length, 0);
+    }
+
+    #[test]
+    fn header_fields() {
+        let mut bytes = vec![0; HEADER_LEN];
+        bytes[0..7].copy_from_slice(b"PMTiles");
+        bytes[7] = 3;
+        bytes[8..16].copy_from_slice(&127_u64.to_le_bytes());
+        bytes[16..24].copy_from_slice(&25_u64.to_le_bytes());
+        bytes[40..48].copy_from_slice(&1000_u64.to_le_bytes());
+        bytes[56..64].copy_from_slice(&2000_u64.to_le_bytes());
+        bytes[97] = 2;
+        bytes[98] = 3;
+        bytes[99] = 1;
+        bytes[100] = 0;
+        bytes[101] = 14;
+        bytes[102..106].copy_from_slice(&(-1_800_000_000_i32).to_le_bytes());
+        bytes[114..118].copy_from_slice(&850_000_000_i32.to_le_bytes());
+        bytes[118] = 5;
+        bytes[119..123].copy_from_slice(&(-1_000_000_i32).to_le_bytes());
+        bytes[123..127].copy_from_slice(&515_000_000_i32.to_le_bytes());
+
+        let header = PmTilesHeader::parse(&bytes).unwrap();
+        assert_eq!(header.root_directory, (127, 25));
+        assert_eq!(header.leaf_directories_offset, 1000);
+        assert_eq!(header.tile_data_offset, 2000);
+        assert_eq!(header.internal_compression, PmTilesCompression::Gzip);
+        assert_eq!(header.tile_compression, PmTilesCompression::Brotli);
+        assert_eq!(header.tile_type, Some(TileType::Vector));
+        assert_eq!((header.min_zoom, header.max_zoom), (0, 14));
+        assert_eq!(header.bounds[0], -180.0);
+        assert_eq!(header.bounds[3], 85.0);
+        assert_eq!(header.center_zoom, 5);
+        assert_eq!(header.center, Coord::new(51.5, -0.1));
+
+        bytes[7] = 2;
+        assert!(PmTilesHeader::parse(&bytes).is_err());
+    }
+}
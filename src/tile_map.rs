@@ -1,17 +1,23 @@
+use std::collections::HashMap;
+
 use bevy::{
     input::mouse::MouseWheel, prelude::*, render::view::RenderLayers, window::PrimaryWindow,
 };
 use crossbeam_channel::{bounded, Receiver, Sender};
-use std::thread;
 
 #[cfg(feature = "ui_blocking")]
 use crate::camera::camera_helper::EguiBlockInputState;
 use crate::{
-    api::buffer_to_bevy_image,
+    api::{buffer_to_bevy_image, resample_rgba, TileRequestClient},
+    attribution::AttributionPlugin,
+    download::{send_region_download_progress, RegionDownloads},
+    error::TileError,
+    provider::TileLayer,
     types::{
-        game_to_coord, Coord, InitTileMapPlugin, TileMapResources, UpdateChunkEvent,
-        ZoomChangedEvent,
+        game_to_coord, Coord, InitTileMapPlugin, Tile, TileLoadFailed, TileMapResources,
+        UpdateChunkEvent, ZoomChangedEvent,
     },
+    worker::TileWorkerPool,
     MapViewerMarker,
 };
 
@@ -24,6 +30,21 @@ pub struct TileMapPlugin {
     pub starting_url: Option<String>,
     pub tile_quality: f32,
     pub cache_dir: String,
+    pub worker_threads: usize,
+}
+
+impl Default for TileMapPlugin {
+    fn default() -> Self {
+        let defaults = crate::MapViewerPlugin::default();
+        Self {
+            starting_location: defaults.starting_location,
+            starting_zoom: defaults.starting_zoom,
+            starting_url: defaults.starting_url,
+            tile_quality: defaults.tile_quality,
+            cache_dir: defaults.cache_dir,
+            worker_threads: defaults.worker_threads,
+        }
+    }
 }
 
 impl Plugin for TileMapPlugin {
@@ -31,6 +52,9 @@ impl Plugin for TileMapPlugin {
         let (tx, rx): (ChunkSenderType, ChunkReceiverType) = bounded(10);
         app.insert_resource(ChunkReceiver(rx))
             .insert_resource(ChunkSender(tx))
+            .insert_resource(TileWorkerPool::new(self.worker_threads))
+            .init_resource::<RegionDownloads>()
+            .init_resource::<LayerDepths>()
             .add_plugins(InitTileMapPlugin {
                 starting_location: self.starting_location,
                 starting_zoom: self.starting_zoom,
@@ -38,8 +62,9 @@ impl Plugin for TileMapPlugin {
                 cache_dir: self.cache_dir.clone(),
                 starting_url: self.starting_url.clone(),
             })
+            .add_plugins(AttributionPlugin)
             .insert_resource(Clean::default())
-            .add_systems(Update, detect_zoom_level)
+            .add_systems(Update, (detect_zoom_level, send_region_download_progress))
             .add_systems(
                 FixedUpdate,
                 (
@@ -48,6 +73,7 @@ impl Plugin for TileMapPlugin {
                     clean_tile_map,
                     spawn_chunks_around_middle,
                     spawn_to_needed_chunks,
+                    update_layer_style,
                 )
                     .chain(),
             )
@@ -58,6 +84,7 @@ impl Plugin for TileMapPlugin {
 
 fn spawn_chunks_around_middle(
     chunk_sender: Res<ChunkSender>,
+    worker_pool: Res<TileWorkerPool>,
     mut res_manager: ResMut<TileMapResources>,
     mut camera_event_reader: EventReader<UpdateChunkEvent>,
     mut cooldown: ResMut<MoveCooldown>,
@@ -75,9 +102,11 @@ fn spawn_chunks_around_middle(
             res_manager.zoom_manager.tile_quality,
         );
         let range = 4;
+        let generation = worker_pool.generation();
 
         for y in (chunk_pos.y - range)..=(chunk_pos.y + range) {
             for x in (chunk_pos.x - range)..=(chunk_pos.x + range) {
+                let middle = chunk_pos;
                 let chunk_pos = IVec2::new(x, y);
                 if !res_manager
                     .chunk_manager
@@ -96,20 +125,40 @@ fn spawn_chunks_around_middle(
                         res_manager.zoom_manager.zoom_level,
                         zoom_manager.tile_quality,
                     );
+                    res_manager.chunk_manager.spawned_chunks.insert(chunk_pos);
+                    let tile = position.to_tile_coords(zoom_manager.zoom_level);
+                    // Chunks past the edges of the world, which exist at low
+                    // zoom levels and near the poles, have no tile
+                    let tile_count = 1_i64 << tile.zoom.min(31);
+                    if !(0..tile_count).contains(&(tile.x as i64))
+                        || !(0..tile_count).contains(&(tile.y as i64))
+                    {
+                        continue;
+                    }
+
                     let tile_requester = res_manager.tile_request_client.clone();
-                    thread::spawn(move || {
-                        let tile_coords = position.to_tile_coords(zoom_manager.zoom_level);
-                        let _ = tx.send((
-                            chunk_pos,
-                            tile_requester.get_tile(
-                                tile_coords.x as u64,
-                                tile_coords.y as u64,
-                                zoom_manager.zoom_level as u64,
-                            ),
-                        ));
+                    let layers = tile_requester.get_enabled_tile_web_origins();
+                    // Tiles closest to the middle of the screen are loaded first.
+                    // The distance is taken now, so after a pan tiles still
+                    // queued for the earlier viewport keep their place
+                    let priority = (chunk_pos - middle).length_squared() as u32;
+                    worker_pool.spawn(priority, move || {
+                        for layer in layers {
+                            let result = tile_requester.get_tile(
+                                &layer.url,
+                                tile.x as u64,
+                                tile.y as u64,
+                                tile.zoom as u64,
+                            );
+                            let _ = tx.send(ChunkData {
+                                chunk_pos,
+                                layer: layer.url,
+                                tile,
+                                generation,
+                                result,
+                            });
+                        }
                     });
-
-                    res_manager.chunk_manager.spawned_chunks.insert(chunk_pos);
                 }
             }
         }
@@ -155,6 +204,7 @@ fn detect_zoom_level(
     evr_scroll: EventReader<MouseWheel>,
     mut zoom_event: EventWriter<ZoomChangedEvent>,
     mut chunk_writer: EventWriter<UpdateChunkEvent>,
+    worker_pool: Res<TileWorkerPool>,
 ) {
     if !evr_scroll.is_empty() {
         cooldown.0.reset();
@@ -177,12 +227,16 @@ fn detect_zoom_level(
                 / res_manager.zoom_manager.scale.x;
 
             while !(3. ..=7.).contains(&width) {
-                if width > 7. && res_manager.zoom_manager.zoom_level > 3 {
+                if width > 7.
+                    && res_manager.zoom_manager.zoom_level > res_manager.zoom_manager.min_zoom
+                {
                     res_manager.zoom_manager.zoom_level -= 1;
                     res_manager.zoom_manager.scale *= 2.0;
                     res_manager.chunk_manager.refrence_long_lat *= Coord { lat: 2., long: 2. };
                     changed = true;
-                } else if width < 3. && res_manager.zoom_manager.zoom_level < 20 {
+                } else if width < 3.
+                    && res_manager.zoom_manager.zoom_level < res_manager.zoom_manager.max_zoom
+                {
                     res_manager.zoom_manager.scale /= 2.0;
                     res_manager.zoom_manager.zoom_level += 1;
                     res_manager.chunk_manager.refrence_long_lat /= Coord { lat: 2., long: 2. };
@@ -217,6 +271,8 @@ fn detect_zoom_level(
                 chunk_writer.write(UpdateChunkEvent);
                 res_manager.chunk_manager.spawned_chunks.clear();
                 res_manager.chunk_manager.to_spawn_chunks.clear();
+                // Tiles of the previous zoom level are no longer needed
+                worker_pool.cancel_pending();
                 cooldown.0.reset();
             }
         } else {
@@ -225,6 +281,9 @@ fn detect_zoom_level(
     }
     if res_manager.tile_request_client.tile_web_origin_changed {
         res_manager.tile_request_client.tile_web_origin_changed = false;
+        // The layers may also have been changed without the client's methods
+        res_manager.tile_request_client.mark_layers_changed();
+        worker_pool.cancel_pending();
         chunk_writer.write(UpdateChunkEvent);
         clean.clean = true;
         cooldown.0.reset();
@@ -233,7 +292,16 @@ fn detect_zoom_level(
 
 // Chunk handling //
 
-type ChunkData = (IVec2, Result<Vec<u8>, image::ImageError>);
+struct ChunkData {
+    chunk_pos: IVec2,
+    // Url of the layer the tile was fetched for
+    layer: String,
+    tile: Tile,
+    // Worker pool generation the request was made in
+    generation: u64,
+    // `None` if the layer has no data for the tile
+    result: Result<Option<Vec<u8>>, TileError>,
+}
 type ChunkSenderType = Sender<ChunkData>;
 type ChunkReceiverType = Receiver<ChunkData>;
 
@@ -245,11 +313,15 @@ struct ChunkLayer(f32, IVec2);
 #[derive(Component)]
 struct TileMarker;
 
+// Url of the tile layer a chunk sprite belongs to
+#[derive(Component)]
+struct TileLayerId(String);
+
 #[derive(Resource, Deref)]
-struct ChunkReceiver(Receiver<(IVec2, Result<Vec<u8>, image::ImageError>)>); // Use Vec<u8> for raw image data
+struct ChunkReceiver(ChunkReceiverType); // Use Vec<u8> for raw image data
 
 #[derive(Resource, Deref)]
-struct ChunkSender(Sender<(IVec2, Result<Vec<u8>, image::ImageError>)>);
+struct ChunkSender(ChunkSenderType);
 
 fn camera_pos_to_chunk_pos(camera_pos: &Vec2, tile_quality: f32) -> IVec2 {
     let camera_pos = Vec2::new(camera_pos.x, camera_pos.y) / tile_quality;
@@ -265,24 +337,37 @@ fn chunk_pos_to_world_pos(chunk_pos: IVec2, tile_quality: f32) -> Vec2 {
 
 fn read_tile_map_receiver(
     map_receiver: Res<ChunkReceiver>,
+    worker_pool: Res<TileWorkerPool>,
     mut res_manager: ResMut<TileMapResources>,
+    mut failed_writer: EventWriter<TileLoadFailed>,
 ) {
     let mut new_chunks = Vec::new();
-    while let Ok((chunk_pos, raw_image_data)) = map_receiver.try_recv() {
-        if !res_manager
-            .chunk_manager
-            .to_spawn_chunks
-            .contains_key(&chunk_pos)
-            && raw_image_data.is_ok()
-        {
-            new_chunks.push((chunk_pos, raw_image_data));
+    let generation = worker_pool.generation();
+    while let Ok(chunk) = map_receiver.try_recv() {
+        // Requested for a zoom level or layer stack that is no longer shown
+        if chunk.generation != generation {
+            continue;
+        }
+        match chunk.result {
+            Ok(None) => {}
+            Ok(Some(data)) => {
+                let key = (chunk.chunk_pos, chunk.layer);
+                if !res_manager.chunk_manager.to_spawn_chunks.contains_key(&key) {
+                    new_chunks.push((key, data));
+                }
+            }
+            Err(error) => {
+                failed_writer.write(TileLoadFailed {
+                    tile: chunk.tile,
+                    layer: chunk.layer,
+                    error,
+                });
+            }
         }
     }
 
-    for (pos, data) in new_chunks {
-        if let Ok(data) = data {
-            res_manager.chunk_manager.to_spawn_chunks.insert(pos, data);
-        }
+    for (key, data) in new_chunks {
+        res_manager.chunk_manager.to_spawn_chunks.insert(key, data);
     }
 }
 
@@ -290,35 +375,78 @@ fn spawn_to_needed_chunks(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
     mut res_manager: ResMut<TileMapResources>,
+    mut layer_depths: ResMut<LayerDepths>,
 ) {
-    let to_spawn_chunks: Vec<(IVec2, Vec<u8>)> = res_manager
-        .chunk_manager
-        .to_spawn_chunks
-        .iter()
-        .map(|(pos, data)| (*pos, data.clone()))
-        .collect();
-    for (chunk_pos, raw_image_data) in to_spawn_chunks {
-        let tile_handle = images.add(buffer_to_bevy_image(
-            raw_image_data,
-            res_manager.zoom_manager.tile_quality as u32,
-        ));
+    let to_spawn_chunks: Vec<((IVec2, String), Vec<u8>)> =
+        res_manager.chunk_manager.to_spawn_chunks.drain().collect();
+    for ((chunk_pos, layer_url), raw_image_data) in to_spawn_chunks {
+        // The layer was removed or disabled while the tile was loading
+        let Some(layer) = res_manager
+            .tile_request_client
+            .get_tile_layer(&layer_url)
+            .filter(|layer| layer.enabled)
+            .cloned()
+        else {
+            continue;
+        };
+        // The tile size of the layer changed while the tile was loading
+        let raw_image_data =
+            if raw_image_data.len() != (layer.tile_size * layer.tile_size * 4) as usize {
+                match resample_rgba(raw_image_data, layer.tile_size) {
+                    Some(resampled) => resampled,
+                    None => continue,
+                }
+            } else {
+                raw_image_data
+            };
+        let depth = layer_depths.get(&res_manager.tile_request_client, &layer.url);
+        let tile_handle = images.add(buffer_to_bevy_image(raw_image_data, layer.tile_size));
         res_manager.chunk_manager.spawned_chunks.insert(chunk_pos);
         spawn_chunk(
             &mut commands,
             tile_handle,
             chunk_pos,
+            &layer,
+            depth,
             res_manager.zoom_manager.tile_quality,
             res_manager.zoom_manager.scale,
             res_manager.chunk_manager.displacement,
         );
     }
-    res_manager.chunk_manager.to_spawn_chunks.clear();
 }
 
+// Offsets added to the zoom layer so that the layer stack is drawn in order,
+// computed again only when the layers of the client change.
+#[derive(Resource, Default)]
+struct LayerDepths {
+    generation: Option<u64>,
+    depths: HashMap<String, f32>,
+}
+
+impl LayerDepths {
+    fn get(&mut self, client: &TileRequestClient, layer_url: &str) -> f32 {
+        if self.generation != Some(client.layers_generation()) {
+            self.generation = Some(client.layers_generation());
+            // Zoom layers are 1.0 apart, so the offset has to stay below that
+            let layers = client.get_enabled_tile_web_origins();
+            let count = layers.len() as f32 + 1.0;
+            self.depths = layers
+                .into_iter()
+                .enumerate()
+                .map(|(rank, layer)| (layer.url, rank as f32 / count))
+                .collect();
+        }
+        self.depths.get(layer_url).copied().unwrap_or(0.0)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn spawn_chunk(
     commands: &mut Commands,
     tile: Handle<Image>,
     chunk_pos: IVec2,
+    layer: &TileLayer,
+    depth: f32,
     tile_quality: f32,
     scale: Vec3,
     offset: Vec2,
@@ -327,16 +455,61 @@ fn spawn_chunk(
     let world_y = chunk_pos.y as f32 * tile_quality * scale.x - offset.y;
     commands.spawn((
         (
-            Sprite::from_image(tile),
-            Transform::from_translation(Vec3::new(world_x, world_y, scale.z)).with_scale(scale),
-            Visibility::Visible,
+            Sprite {
+                color: Color::srgba(1.0, 1.0, 1.0, layer.opacity),
+                // The texture can have any resolution, the chunk is always
+                // `tile_quality` wide
+                custom_size: Some(Vec2::splat(tile_quality)),
+                ..Sprite::from_image(tile)
+            },
+            Transform::from_translation(Vec3::new(world_x, world_y, scale.z + depth))
+                .with_scale(scale),
+            layer_visibility(layer),
         ),
         ChunkLayer(scale.z, chunk_pos),
+        TileLayerId(layer.url.clone()),
         TileMarker,
         RenderLayers::layer(0),
     ));
 }
 
+fn layer_visibility(layer: &TileLayer) -> Visibility {
+    if layer.visible {
+        Visibility::Visible
+    } else {
+        Visibility::Hidden
+    }
+}
+
+// Applies opacity, visibility and order changes without fetching the tiles again
+fn update_layer_style(
+    mut res_manager: ResMut<TileMapResources>,
+    mut layer_depths: ResMut<LayerDepths>,
+    mut chunk_query: Query<(
+        &TileLayerId,
+        &ChunkLayer,
+        &mut Sprite,
+        &mut Transform,
+        &mut Visibility,
+    )>,
+) {
+    if !res_manager.tile_request_client.tile_layer_style_changed {
+        return;
+    }
+    res_manager.tile_request_client.tile_layer_style_changed = false;
+    res_manager.tile_request_client.mark_layers_changed();
+
+    let client = &res_manager.tile_request_client;
+    for (layer_id, chunk_layer, mut sprite, mut transform, mut visibility) in chunk_query.iter_mut()
+    {
+        if let Some(layer) = client.get_tile_layer(&layer_id.0) {
+            sprite.color.set_alpha(layer.opacity);
+            transform.translation.z = chunk_layer.0 + layer_depths.get(client, &layer.url);
+            *visibility = layer_visibility(layer);
+        }
+    }
+}
+
 // Despawn handling //
 
 fn despawn_outofrange_chunks(
@@ -14,7 +14,7 @@ use std::{
     ops::{AddAssign, DivAssign, MulAssign, SubAssign},
 };
 
-use crate::api::TileRequestClient;
+use crate::{api::TileRequestClient, download::RegionDownloadProgress, error::TileError};
 
 #[derive(Component, Debug, Clone)]
 pub struct MapViewerMarker;
@@ -38,6 +38,8 @@ impl Plugin for InitTileMapPlugin {
         ))
         .add_event::<ZoomChangedEvent>()
         .add_event::<UpdateChunkEvent>()
+        .add_event::<TileLoadFailed>()
+        .add_event::<RegionDownloadProgress>()
         .add_systems(Startup, send_initial_events);
     }
 }
@@ -58,11 +60,21 @@ impl TileMapResources {
         tile_quality: f32,
         cache_dir: String,
     ) -> Self {
+        let tile_request_client = TileRequestClient::new(cache_dir, starting_url);
+        let mut zoom_manager = ZoomManager::new(zoom, tile_quality);
+        let mut location = starting_location;
+        if let Some(view) = tile_request_client.archive_view() {
+            zoom_manager = ZoomManager::new(view.zoom, tile_quality);
+            zoom_manager.min_zoom = view.min_zoom;
+            zoom_manager.max_zoom = view.max_zoom;
+            location = view.center;
+        }
+
         Self {
-            zoom_manager: ZoomManager::new(zoom, tile_quality),
+            zoom_manager,
             chunk_manager: ChunkManager::new(),
-            location_manager: Location::new(starting_location),
-            tile_request_client: TileRequestClient::new(cache_dir, starting_url),
+            location_manager: Location::new(location),
+            tile_request_client,
         }
     }
 
@@ -104,6 +116,16 @@ pub struct WorldSpaceRect {
     pub bottom_right: Coord,
 }
 
+/// A `geo::Rect` with longitudes as x and latitudes as y.
+impl From<geo::Rect<f32>> for WorldSpaceRect {
+    fn from(rect: geo::Rect<f32>) -> Self {
+        Self {
+            top_left: Coord::new(rect.max().y, rect.min().x),
+            bottom_right: Coord::new(rect.min().y, rect.max().x),
+        }
+    }
+}
+
 pub fn tile_width_meters(zoom: u32) -> f64 {
     let earth_circumference_meters = 40075016.686;
     let num_tiles = 2_u32.pow(zoom) as f64;
@@ -115,7 +137,7 @@ pub enum DistanceType {
     CM,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TileType {
     Raster,
     Vector,
@@ -327,6 +349,7 @@ impl DivAssign for Coord {
 //------------------------------------------------------------------------------
 // Tile System and Conversions
 //------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Tile {
     pub x: i32,
     pub y: i32,
@@ -432,6 +455,8 @@ pub struct ZoomManager {
     pub scale: Vec3,
     pub tile_quality: f32,
     pub starting_zoom: u32,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
 }
 
 impl Default for ZoomManager {
@@ -441,6 +466,8 @@ impl Default for ZoomManager {
             scale: Vec3::splat(1.0),
             tile_quality: 256_f32,
             starting_zoom: 14,
+            min_zoom: 3,
+            max_zoom: 20,
         }
     }
 }
@@ -449,9 +476,9 @@ impl ZoomManager {
     fn new(zoom: u32, tile_quality: f32) -> Self {
         Self {
             zoom_level: zoom,
-            scale: Vec3::splat(1.0),
             tile_quality,
             starting_zoom: zoom,
+            ..Default::default()
         }
     }
 }
@@ -459,10 +486,19 @@ impl ZoomManager {
 #[derive(Event)]
 pub struct UpdateChunkEvent;
 
+/// Sent when a tile could not be loaded for one of the enabled layers.
+#[derive(Event, Debug)]
+pub struct TileLoadFailed {
+    pub tile: Tile,
+    /// Url of the layer the tile belongs to.
+    pub layer: String,
+    pub error: TileError,
+}
+
 #[derive(Debug, Clone)]
 pub struct ChunkManager {
     pub spawned_chunks: HashSet<IVec2>,
-    pub to_spawn_chunks: HashMap<IVec2, Vec<u8>>, // Store raw image data
+    pub to_spawn_chunks: HashMap<(IVec2, String), Vec<u8>>, // Raw image data per chunk and layer url
     pub refrence_long_lat: Coord,
     pub displacement: Vec2,
     pub layer_management: Vec<f32>,
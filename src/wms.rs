@@ -0,0 +1,131 @@
+//! Layers served by an OGC Web Map Service.
+//!
+//! WMS servers render any requested area instead of serving fixed tiles, so
+//! a WMS layer is a `GetMap` url template asking for the bounds of each tile
+//! in EPSG:3857 through the `{bbox}` placeholder. The tiles are then fetched,
+//! cached and decoded like those of any other raster provider.
+
+use crate::{
+    provider::{encode_query_value, TileLayer},
+    TileType,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WmsVersion {
+    V1_1_1,
+    #[default]
+    V1_3_0,
+}
+
+impl WmsVersion {
+    fn as_str(self) -> &'static str {
+        match self {
+            WmsVersion::V1_1_1 => "1.1.1",
+            WmsVersion::V1_3_0 => "1.3.0",
+        }
+    }
+
+    // 1.3.0 renamed the SRS parameter to CRS
+    fn crs_parameter(self) -> &'static str {
+        match self {
+            WmsVersion::V1_1_1 => "SRS",
+            WmsVersion::V1_3_0 => "CRS",
+        }
+    }
+}
+
+/// The `GetMap` parameters of a WMS layer.
+#[derive(Debug, Clone)]
+pub struct WmsLayer {
+    /// The service endpoint, which may already hold query parameters such as
+    /// a `map` path.
+    pub url: String,
+    pub version: WmsVersion,
+    pub layers: Vec<String>,
+    /// One style per layer, the default style of every layer if empty.
+    pub styles: Vec<String>,
+    /// Image format, such as `image/png` or `image/jpeg`.
+    pub format: String,
+    /// Ask for a transparent background, for layers drawn over others.
+    pub transparent: bool,
+    /// Width and height of the requested images in pixels.
+    pub tile_size: u32,
+}
+
+impl WmsLayer {
+    pub fn new(url: impl Into<String>, layers: &[&str]) -> Self {
+        Self {
+            url: url.into(),
+            version: WmsVersion::default(),
+            layers: layers.iter().map(|layer| layer.to_string()).collect(),
+            styles: Vec::new(),
+            format: "image/png".to_string(),
+            transparent: false,
+            tile_size: 256,
+        }
+    }
+
+    /// The `GetMap` url template, with `{bbox}` in place of the tile bounds.
+    pub fn url_template(&self) -> String {
+        let separator = match self.url.contains('?') {
+            true if self.url.ends_with(['?', '&']) => "",
+            true => "&",
+            false => "?",
+        };
+        let join = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| encode_query_value(value))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        format!(
+            "{}{}SERVICE=WMS&REQUEST=GetMap&VERSION={}&LAYERS={}&STYLES={}&FORMAT={}&TRANSPARENT={}&{}=EPSG:3857&BBOX={{bbox}}&WIDTH={}&HEIGHT={}",
+            self.url,
+            separator,
+            self.version.as_str(),
+            join(&self.layers),
+            join(&self.styles),
+            encode_query_value(&self.format),
+            if self.transparent { "TRUE" } else { "FALSE" },
+            self.version.crs_parameter(),
+            self.tile_size,
+            self.tile_size,
+        )
+    }
+
+    /// A raster layer requesting its tiles from the service.
+    pub fn into_tile_layer(self) -> TileLayer {
+        TileLayer::new(self.url_template(), TileType::Raster).with_tile_size(self.tile_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_name_the_projection_parameter() {
+        let mut layer = WmsLayer::new("https://example.com/wms", &["roads", "ws:rivers"]);
+        assert_eq!(
+            layer.url_template(),
+            "https://example.com/wms?SERVICE=WMS&REQUEST=GetMap&VERSION=1.3.0&LAYERS=roads,ws:rivers&STYLES=&FORMAT=image%2Fpng&TRANSPARENT=FALSE&CRS=EPSG:3857&BBOX={bbox}&WIDTH=256&HEIGHT=256"
+        );
+        layer.version = WmsVersion::V1_1_1;
+        let template = layer.url_template();
+        assert!(template.contains("&VERSION=1.1.1&"));
+        assert!(template.contains("&SRS=EPSG:3857&"));
+        assert!(!template.contains("CRS="));
+    }
+
+    #[test]
+    fn existing_queries_are_extended() {
+        let template = |url: &str| WmsLayer::new(url, &["roads"]).url_template();
+        assert!(template("https://example.com/cgi?map=/maps/roads.map")
+            .starts_with("https://example.com/cgi?map=/maps/roads.map&SERVICE=WMS&"));
+        assert!(template("https://example.com/cgi?map=roads&")
+            .starts_with("https://example.com/cgi?map=roads&SERVICE=WMS&"));
+        assert!(template("https://example.com/wms?")
+            .starts_with("https://example.com/wms?SERVICE=WMS&"));
+    }
+}
@@ -0,0 +1,451 @@
+//! Layer discovery through the capabilities document of an OGC Web Map Tile
+//! Service.
+//!
+//! WMTS layers are served in tile matrix sets. Only sets matching the web
+//! mercator grid of the viewer, usually called `GoogleMapsCompatible`, can be
+//! shown. Their tiles are requested through the `ResourceURL` template of the
+//! layer, or through KVP `GetTile` requests for services without one.
+
+use roxmltree::{Document, Node};
+
+use crate::{
+    error::TileError,
+    provider::{encode_query_value, TileLayer},
+    TileType,
+};
+
+/// The parts of a `GetCapabilities` response needed to pick and show a layer.
+#[derive(Debug, Clone, Default)]
+pub struct WmtsCapabilities {
+    pub layers: Vec<WmtsLayer>,
+    pub tile_matrix_sets: Vec<WmtsTileMatrixSet>,
+    /// Endpoint for KVP `GetTile` requests, if the service supports them.
+    pub get_tile_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WmtsLayer {
+    pub identifier: String,
+    pub title: Option<String>,
+    pub styles: Vec<WmtsStyle>,
+    pub formats: Vec<String>,
+    /// Identifiers of the tile matrix sets the layer is served in.
+    pub tile_matrix_sets: Vec<String>,
+    pub resource_urls: Vec<WmtsResourceUrl>,
+    /// Extra dimensions such as `Time`, with their default values.
+    pub dimensions: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WmtsStyle {
+    pub identifier: String,
+    pub title: Option<String>,
+    pub is_default: bool,
+}
+
+/// A RESTful tile url template such as
+/// `https://example.com/wmts/{Layer}/{TileMatrixSet}/{TileMatrix}/{TileRow}/{TileCol}.png`.
+#[derive(Debug, Clone, Default)]
+pub struct WmtsResourceUrl {
+    pub format: String,
+    pub template: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WmtsTileMatrixSet {
+    pub identifier: String,
+    pub supported_crs: String,
+    pub well_known_scale_set: Option<String>,
+    /// Identifiers of the tile matrices, from the lowest zoom level up.
+    pub tile_matrices: Vec<String>,
+    // Size in tiles of the first matrix
+    first_matrix_size: Option<(u64, u64)>,
+}
+
+impl WmtsTileMatrixSet {
+    /// True if the set uses the same tiles as OpenStreetMap and Google Maps,
+    /// the only grid the viewer can show.
+    pub fn is_google_maps_compatible(&self) -> bool {
+        if self
+            .well_known_scale_set
+            .as_deref()
+            .is_some_and(|scale_set| scale_set.ends_with("GoogleMapsCompatible"))
+        {
+            return true;
+        }
+        let web_mercator = ["3857", "900913"]
+            .iter()
+            .any(|code| self.supported_crs.ends_with(code));
+        web_mercator && self.first_matrix_size == Some((1, 1))
+    }
+
+    // The matrix identifier with `{z}` for the zoom level, if the identifiers
+    // are the zoom levels with an optional common prefix, like `EPSG:3857:5`
+    fn tile_matrix_template(&self) -> Option<String> {
+        let first = self.tile_matrices.first()?;
+        let prefix = first.strip_suffix('0')?;
+        self.tile_matrices
+            .iter()
+            .enumerate()
+            .all(|(zoom, identifier)| {
+                identifier.strip_prefix(prefix) == Some(zoom.to_string().as_str())
+            })
+            .then(|| format!("{}{{z}}", prefix))
+    }
+}
+
+impl WmtsCapabilities {
+    pub fn parse(xml: &str) -> Result<Self, TileError> {
+        let document = Document::parse(xml).map_err(|err| TileError::Source(Box::new(err)))?;
+        let root = document.root_element();
+        let contents = child(root, "Contents")
+            .ok_or_else(|| wmts_error("Capabilities document has no Contents"))?;
+
+        Ok(Self {
+            layers: children(contents, "Layer").map(parse_layer).collect(),
+            tile_matrix_sets: children(contents, "TileMatrixSet")
+                .map(parse_tile_matrix_set)
+                .collect(),
+            get_tile_url: get_tile_url(root),
+        })
+    }
+
+    pub fn layer(&self, identifier: &str) -> Option<&WmtsLayer> {
+        self.layers
+            .iter()
+            .find(|layer| layer.identifier == identifier)
+    }
+
+    pub fn tile_matrix_set(&self, identifier: &str) -> Option<&WmtsTileMatrixSet> {
+        self.tile_matrix_sets
+            .iter()
+            .find(|set| set.identifier == identifier)
+    }
+
+    /// A tile layer showing the given WMTS layer, in the style given or its
+    /// default style. Fails if the layer is not served in a
+    /// `GoogleMapsCompatible` tile matrix set.
+    pub fn tile_layer(
+        &self,
+        identifier: &str,
+        style: Option<&str>,
+    ) -> Result<TileLayer, TileError> {
+        let layer = self
+            .layer(identifier)
+            .ok_or_else(|| wmts_error(format!("No WMTS layer {}", identifier)))?;
+        let (set, matrix_template) = layer
+            .tile_matrix_sets
+            .iter()
+            .filter_map(|identifier| self.tile_matrix_set(identifier))
+            .filter(|set| set.is_google_maps_compatible())
+            .find_map(|set| Some((set, set.tile_matrix_template()?)))
+            .ok_or_else(|| {
+                wmts_error(format!(
+                    "WMTS layer {} has no GoogleMapsCompatible tile matrix set",
+                    identifier
+                ))
+            })?;
+        let style = match style {
+            Some(style) => style.to_string(),
+            None => layer
+                .styles
+                .iter()
+                .find(|style| style.is_default)
+                .or(layer.styles.first())
+                .map(|style| style.identifier.clone())
+                .unwrap_or_else(|| "default".to_string()),
+        };
+
+        let (format, template) = match preferred_resource_url(layer) {
+            Some(resource) => (resource.format.clone(), resource.template.clone()),
+            None => {
+                let endpoint = self.get_tile_url.as_ref().ok_or_else(|| {
+                    wmts_error(format!("WMTS layer {} has no tile url", identifier))
+                })?;
+                let format = preferred_format(&layer.formats).ok_or_else(|| {
+                    wmts_error(format!("WMTS layer {} has no format", identifier))
+                })?;
+                let endpoint = endpoint.trim_end_matches(['?', '&']);
+                let separator = if endpoint.contains('?') { "&" } else { "?" };
+                // Unlike in ResourceURL paths, the values are query parameters
+                let template = format!(
+                    "{}{}SERVICE=WMTS&REQUEST=GetTile&VERSION=1.0.0&LAYER={}&STYLE={}&TILEMATRIXSET={{TileMatrixSet}}&TILEMATRIX={{TileMatrix}}&TILEROW={{TileRow}}&TILECOL={{TileCol}}&FORMAT={}",
+                    endpoint, separator,
+                    encode_query_value(&layer.identifier),
+                    encode_query_value(&style),
+                    format
+                );
+                (format.to_string(), template)
+            }
+        };
+
+        let mut url = template
+            .replace("{Layer}", &layer.identifier)
+            .replace("{Style}", &style)
+            .replace("{TileMatrixSet}", &set.identifier)
+            .replace("{TileMatrix}", &matrix_template)
+            .replace("{TileRow}", "{y}")
+            .replace("{TileCol}", "{x}");
+        for (dimension, default) in &layer.dimensions {
+            url = url.replace(&format!("{{{}}}", dimension), default);
+        }
+
+        let tile_type = if is_vector_format(&format) {
+            TileType::Vector
+        } else {
+            TileType::Raster
+        };
+        Ok(TileLayer::new(url, tile_type))
+    }
+}
+
+fn parse_layer(node: Node) -> WmtsLayer {
+    WmtsLayer {
+        identifier: child_text(node, "Identifier").unwrap_or_default(),
+        title: child_text(node, "Title"),
+        styles: children(node, "Style")
+            .map(|style| WmtsStyle {
+                identifier: child_text(style, "Identifier").unwrap_or_default(),
+                title: child_text(style, "Title"),
+                is_default: style.attribute("isDefault") == Some("true"),
+            })
+            .collect(),
+        formats: children(node, "Format").filter_map(text).collect(),
+        tile_matrix_sets: children(node, "TileMatrixSetLink")
+            .filter_map(|link| child_text(link, "TileMatrixSet"))
+            .collect(),
+        resource_urls: children(node, "ResourceURL")
+            .filter(|resource| resource.attribute("resourceType") == Some("tile"))
+            .filter_map(|resource| {
+                Some(WmtsResourceUrl {
+                    format: resource.attribute("format").unwrap_or_default().to_string(),
+                    template: resource.attribute("template")?.to_string(),
+                })
+            })
+            .collect(),
+        dimensions: children(node, "Dimension")
+            .filter_map(|dimension| {
+                Some((
+                    child_text(dimension, "Identifier")?,
+                    child_text(dimension, "Default")?,
+                ))
+            })
+            .collect(),
+    }
+}
+
+fn parse_tile_matrix_set(node: Node) -> WmtsTileMatrixSet {
+    let matrices: Vec<Node> = children(node, "TileMatrix").collect();
+    WmtsTileMatrixSet {
+        identifier: child_text(node, "Identifier").unwrap_or_default(),
+        supported_crs: child_text(node, "SupportedCRS").unwrap_or_default(),
+        well_known_scale_set: child_text(node, "WellKnownScaleSet"),
+        tile_matrices: matrices
+            .iter()
+            .filter_map(|matrix| child_text(*matrix, "Identifier"))
+            .collect(),
+        first_matrix_size: matrices.first().and_then(|matrix| {
+            Some((
+                child_text(*matrix, "MatrixWidth")?.parse().ok()?,
+                child_text(*matrix, "MatrixHeight")?.parse().ok()?,
+            ))
+        }),
+    }
+}
+
+// The KVP endpoint listed for GetTile under OperationsMetadata
+fn get_tile_url(root: Node) -> Option<String> {
+    let operation = child(root, "OperationsMetadata")
+        .into_iter()
+        .flat_map(|metadata| children(metadata, "Operation"))
+        .find(|operation| operation.attribute("name") == Some("GetTile"))?;
+    operation
+        .descendants()
+        .filter(|node| node.tag_name().name() == "Get")
+        .find(|get| {
+            // Endpoints restricted to RESTful encoding can't take KVP requests
+            get.descendants()
+                .filter(|node| node.tag_name().name() == "Value")
+                .all(|value| value.text().map(str::trim) == Some("KVP"))
+        })
+        .and_then(|get| {
+            get.attributes()
+                .find(|attribute| attribute.name() == "href")
+                .map(|attribute| attribute.value().to_string())
+        })
+}
+
+// Images are preferred, as they need no rasterizing
+fn preferred_resource_url(layer: &WmtsLayer) -> Option<&WmtsResourceUrl> {
+    layer
+        .resource_urls
+        .iter()
+        .find(|resource| resource.format.starts_with("image/"))
+        .or(layer.resource_urls.first())
+}
+
+fn preferred_format(formats: &[String]) -> Option<&str> {
+    formats
+        .iter()
+        .find(|format| format.starts_with("image/"))
+        .or(formats.first())
+        .map(String::as_str)
+}
+
+fn is_vector_format(format: &str) -> bool {
+    format.contains("vector-tile") || format.contains("protobuf") || format.contains("pbf")
+}
+
+// Elements are matched by local name, services differ in namespace prefixes
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    child(node, name).and_then(text)
+}
+
+fn text(node: Node) -> Option<String> {
+    let text = node.text()?.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn wmts_error(message: impl Into<String>) -> TileError {
+    TileError::Source(message.into().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPABILITIES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+  <ows:OperationsMetadata>
+    <ows:Operation name="GetTile">
+      <ows:DCP><ows:HTTP>
+        <ows:Get xlink:href="https://example.com/wmts?">
+          <ows:Constraint name="GetEncoding">
+            <ows:AllowedValues><ows:Value>KVP</ows:Value></ows:AllowedValues>
+          </ows:Constraint>
+        </ows:Get>
+      </ows:HTTP></ows:DCP>
+    </ows:Operation>
+  </ows:OperationsMetadata>
+  <Contents>
+    <Layer>
+      <ows:Title>Orthophotos</ows:Title>
+      <ows:Identifier>ortho</ows:Identifier>
+      <Style isDefault="true"><ows:Identifier>normal</ows:Identifier></Style>
+      <Format>image/jpeg</Format>
+      <Dimension><ows:Identifier>Time</ows:Identifier><Default>2024</Default></Dimension>
+      <TileMatrixSetLink><TileMatrixSet>EPSG:2056</TileMatrixSet></TileMatrixSetLink>
+      <TileMatrixSetLink><TileMatrixSet>WebMercator</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/jpeg" resourceType="tile" template="https://example.com/wmts/{Layer}/{Style}/{Time}/{TileMatrixSet}/{TileMatrix}/{TileRow}/{TileCol}.jpeg"/>
+    </Layer>
+    <Layer>
+      <ows:Identifier>roads</ows:Identifier>
+      <Style><ows:Identifier>dark</ows:Identifier></Style>
+      <Format>application/vnd.mapbox-vector-tile</Format>
+      <TileMatrixSetLink><TileMatrixSet>GoogleMapsCompatible</TileMatrixSet></TileMatrixSetLink>
+    </Layer>
+    <Layer>
+      <ows:Identifier>local</ows:Identifier>
+      <Format>image/png</Format>
+      <TileMatrixSetLink><TileMatrixSet>EPSG:2056</TileMatrixSet></TileMatrixSetLink>
+    </Layer>
+    <TileMatrixSet>
+      <ows:Identifier>EPSG:2056</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::2056</ows:SupportedCRS>
+      <TileMatrix><ows:Identifier>0</ows:Identifier><MatrixWidth>1</MatrixWidth><MatrixHeight>1</MatrixHeight></TileMatrix>
+    </TileMatrixSet>
+    <TileMatrixSet>
+      <ows:Identifier>WebMercator</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>
+      <TileMatrix><ows:Identifier>WebMercator:0</ows:Identifier><MatrixWidth>1</MatrixWidth><MatrixHeight>1</MatrixHeight></TileMatrix>
+      <TileMatrix><ows:Identifier>WebMercator:1</ows:Identifier><MatrixWidth>2</MatrixWidth><MatrixHeight>2</MatrixHeight></TileMatrix>
+    </TileMatrixSet>
+    <TileMatrixSet>
+      <ows:Identifier>GoogleMapsCompatible</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>
+      <WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>
+      <TileMatrix><ows:Identifier>0</ows:Identifier><MatrixWidth>1</MatrixWidth><MatrixHeight>1</MatrixHeight></TileMatrix>
+      <TileMatrix><ows:Identifier>1</ows:Identifier><MatrixWidth>2</MatrixWidth><MatrixHeight>2</MatrixHeight></TileMatrix>
+    </TileMatrixSet>
+  </Contents>
+</Capabilities>"#;
+
+    #[test]
+    fn parses_layers_and_sets() {
+        let capabilities = WmtsCapabilities::parse(CAPABILITIES).unwrap();
+        assert_eq!(capabilities.layers.len(), 3);
+        assert_eq!(
+            capabilities.get_tile_url.as_deref(),
+            Some("https://example.com/wmts?")
+        );
+
+        let ortho = capabilities.layer("ortho").unwrap();
+        assert_eq!(ortho.title.as_deref(), Some("Orthophotos"));
+        assert!(ortho.styles[0].is_default);
+        assert_eq!(ortho.tile_matrix_sets, ["EPSG:2056", "WebMercator"]);
+        assert_eq!(ortho.dimensions, [("Time".to_string(), "2024".to_string())]);
+
+        let web_mercator = capabilities.tile_matrix_set("WebMercator").unwrap();
+        assert_eq!(
+            web_mercator.tile_matrices,
+            ["WebMercator:0", "WebMercator:1"]
+        );
+        assert!(web_mercator.is_google_maps_compatible());
+        assert!(capabilities
+            .tile_matrix_set("GoogleMapsCompatible")
+            .unwrap()
+            .is_google_maps_compatible());
+        assert!(!capabilities
+            .tile_matrix_set("EPSG:2056")
+            .unwrap()
+            .is_google_maps_compatible());
+    }
+
+    #[test]
+    fn resource_url_layers_use_the_template() {
+        let capabilities = WmtsCapabilities::parse(CAPABILITIES).unwrap();
+        let layer = capabilities.tile_layer("ortho", None).unwrap();
+        assert_eq!(
+            layer.url,
+            "https://example.com/wmts/ortho/normal/2024/WebMercator/WebMercator:{z}/{y}/{x}.jpeg"
+        );
+        assert_eq!(layer.tile_type, TileType::Raster);
+    }
+
+    #[test]
+    fn kvp_layers_use_get_tile() {
+        let capabilities = WmtsCapabilities::parse(CAPABILITIES).unwrap();
+        let layer = capabilities.tile_layer("roads", Some("light")).unwrap();
+        assert_eq!(
+            layer.url,
+            "https://example.com/wmts?SERVICE=WMTS&REQUEST=GetTile&VERSION=1.0.0&LAYER=roads&STYLE=light&TILEMATRIXSET=GoogleMapsCompatible&TILEMATRIX={z}&TILEROW={y}&TILECOL={x}&FORMAT=application/vnd.mapbox-vector-tile"
+        );
+        assert_eq!(layer.tile_type, TileType::Vector);
+
+        let layer = capabilities
+            .tile_layer("roads", Some("light&dark"))
+            .unwrap();
+        assert!(layer.url.contains("&STYLE=light%26dark&"));
+    }
+
+    #[test]
+    fn layers_without_a_web_mercator_set_are_rejected() {
+        let capabilities = WmtsCapabilities::parse(CAPABILITIES).unwrap();
+        assert!(capabilities.tile_layer("local", None).is_err());
+        assert!(capabilities.tile_layer("missing", None).is_err());
+        assert!(WmtsCapabilities::parse("<Capabilities/>").is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! A fixed number of threads that fetch and decode tiles.
+//!
+//! Tile requests are queued as jobs and picked up by the first free worker, so
+//! the number of concurrent network requests and MVT rasterizations never
+//! exceeds the size of the pool.
+//!
+//! The queue is ordered by priority, lowest first, so the tiles closest to the
+//! middle of the screen are loaded before the ones at the edges. Jobs queued
+//! before a call to [`TileWorkerPool::cancel_pending`] are dropped, and the
+//! generation they were spawned in lets the results of jobs that were already
+//! running be told apart from current ones.
+
+use bevy::{ecs::resource::Resource, log::error};
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Resource)]
+pub struct TileWorkerPool {
+    shared: Arc<Shared>,
+    threads: usize,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    generation: AtomicU64,
+}
+
+/// Worker count used by [`MapViewerPlugin::default`](crate::MapViewerPlugin).
+pub const DEFAULT_WORKER_THREADS: usize = 4;
+
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<QueuedJob>,
+    next_sequence: u64,
+    closed: bool,
+}
+
+struct QueuedJob {
+    priority: u32,
+    // Among jobs of the same priority the most recent one runs first
+    sequence: u64,
+    job: Job,
+}
+
+impl TileWorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let shared = Arc::new(Shared {
+            queue: Mutex::default(),
+            available: Condvar::new(),
+            generation: AtomicU64::new(0),
+        });
+        for i in 0..threads {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("tile-worker-{}", i))
+                .spawn(move || {
+                    // Exits once the pool is dropped
+                    while let Some(job) = shared.next_job() {
+                        // A panicking job must not take the worker down with it
+                        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            let message = panic
+                                .downcast_ref::<&str>()
+                                .copied()
+                                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                                .unwrap_or("unknown panic");
+                            error!("Tile job panicked: {}", message);
+                        }
+                    }
+                })
+                .expect("Failed to spawn tile worker thread");
+        }
+        Self { shared, threads }
+    }
+
+    /// Queues a job, jobs with a lower `priority` are run first.
+    ///
+    /// The priority is fixed when the job is queued. Once the camera pans,
+    /// jobs of the earlier viewport keep their place and still run before the
+    /// farther tiles of the new one, until [`TileWorkerPool::cancel_pending`].
+    pub fn spawn(&self, priority: u32, job: impl FnOnce() + Send + 'static) {
+        let mut queue = self.shared.lock();
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.jobs.push(QueuedJob {
+            priority,
+            sequence,
+            job: Box::new(job),
+        });
+        self.shared.available.notify_one();
+    }
+
+    /// Drops all queued jobs and starts a new generation. Jobs that are
+    /// already running are left to finish.
+    pub fn cancel_pending(&self) {
+        let mut queue = self.shared.lock();
+        queue.jobs.clear();
+        self.shared.generation.fetch_add(1, AtomicOrdering::SeqCst);
+    }
+
+    /// Incremented by every [`TileWorkerPool::cancel_pending`]. Results of
+    /// jobs spawned in an earlier generation are stale.
+    pub fn generation(&self) -> u64 {
+        self.shared.generation.load(AtomicOrdering::SeqCst)
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Number of jobs waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.shared.lock().jobs.len()
+    }
+}
+
+impl Drop for TileWorkerPool {
+    fn drop(&mut self) {
+        let mut queue = self.shared.lock();
+        queue.closed = true;
+        queue.jobs.clear();
+        self.shared.available.notify_all();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Blocks until a job is queued, `None` once the pool is dropped
+    fn next_job(&self) -> Option<Job> {
+        let mut queue = self.lock();
+        loop {
+            if queue.closed {
+                return None;
+            }
+            if let Some(queued) = queue.jobs.pop() {
+                return Some(queued.job);
+            }
+            queue = self
+                .available
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+// `BinaryHeap` is a max-heap, so the lowest priority compares as the greatest
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedJob {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn queued(priority: u32, sequence: u64) -> QueuedJob {
+        QueuedJob {
+            priority,
+            sequence,
+            job: Box::new(|| {}),
+        }
+    }
+
+    #[test]
+    fn lowest_priority_pops_first() {
+        let mut jobs = BinaryHeap::new();
+        for (priority, sequence) in [(5, 0), (1, 1), (9, 2), (1, 3), (0, 4)] {
+            jobs.push(queued(priority, sequence));
+        }
+        let order: Vec<(u32, u64)> = std::iter::from_fn(|| jobs.pop())
+            .map(|job| (job.priority, job.sequence))
+            .collect();
+        // Ties go to the most recently queued job
+        assert_eq!(order, [(0, 4), (1, 3), (1, 1), (5, 0), (9, 2)]);
+    }
+
+    #[test]
+    fn cancel_pending_drops_queued_jobs() {
+        let pool = TileWorkerPool::new(1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        // Keeps the only worker busy while the other jobs are queued
+        pool.spawn(0, move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started_rx.recv().unwrap();
+
+        let (ran_tx, ran_rx) = mpsc::channel();
+        for priority in 0..3 {
+            let ran_tx = ran_tx.clone();
+            pool.spawn(priority, move || ran_tx.send(priority).unwrap());
+        }
+        assert_eq!(pool.queued(), 3);
+        pool.cancel_pending();
+        assert_eq!(pool.queued(), 0);
+        assert_eq!(pool.generation(), 1);
+
+        pool.spawn(10, move || ran_tx.send(10).unwrap());
+        release_tx.send(()).unwrap();
+        assert_eq!(ran_rx.recv().unwrap(), 10);
+    }
+}